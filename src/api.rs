@@ -1,10 +1,13 @@
 use log::{info, warn};
-use std::collections::HashSet;
+use std::{collections::HashSet, error::Error, time::Duration};
+use reqwest::StatusCode;
 use serde::Deserialize;
 
 use caramel::ns::api::{Client, ApiError};
 use caramel::ns::xml::parse_wa_members;
 
+use crate::config::canonicalize_region;
+use crate::metrics::METRICS;
 use crate::tgloop::Telegram;
 
 pub async fn query_wa_nations(
@@ -25,25 +28,124 @@ pub async fn query_wa_nations(
         warn!("Invalid XML from WA members API request");
     }
 
-    return Ok(());
+    Ok(())
+}
+
+/// The outcome of a sendTG request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendResult {
+    /// The telegram was accepted and queued for delivery by NationStates.
+    Queued,
+    /// The client key isn't registered for the API.
+    InvalidClientKey,
+    /// The telegram ID or secret key is wrong.
+    InvalidTemplate,
+    /// The recipient nation doesn't exist.
+    NoSuchNation,
+    /// The client has hit the telegram rate limit, optionally with how long to wait before retrying.
+    RateLimited(Option<Duration>),
+    /// Anything else, such as a network error or NationStates being down. Worth retrying later.
+    ServerError(String),
+    /// The telegram wasn't sent because the recipient no longer qualifies for it.
+    Skipped(String),
+}
+
+impl std::fmt::Display for SendResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Queued => write!(f, "queued"),
+            Self::InvalidClientKey => write!(f, "client key not registered for the API"),
+            Self::InvalidTemplate => write!(f, "invalid telegram ID or secret key"),
+            Self::NoSuchNation => write!(f, "recipient nation doesn't exist"),
+            Self::RateLimited(_) => write!(f, "rate limited"),
+            Self::ServerError(err) => write!(f, "server error: {}", err),
+            Self::Skipped(reason) => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+impl SendResult {
+    /// Short name of the outcome, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::InvalidClientKey => "invalid_client_key",
+            Self::InvalidTemplate => "invalid_template",
+            Self::NoSuchNation => "no_such_nation",
+            Self::RateLimited(_) => "rate_limited",
+            Self::ServerError(_) => "server_error",
+            Self::Skipped(_) => "skipped",
+        }
+    }
+}
+
+/// Extracts the number of seconds from a "Retry-After: N" style hint, if there is one.
+fn parse_retry_after(text: &str) -> Option<Duration> {
+    let lower = text.to_lowercase();
+    let rest = &lower[lower.find("retry-after")? + "retry-after".len()..];
+    let digits: String = rest.trim_start_matches(|c: char| !c.is_ascii_digit())
+        .chars().take_while(|c| c.is_ascii_digit()).collect();
+
+    digits.parse().ok().map(Duration::from_secs)
+}
+
+fn classify_send_response(response: &str) -> SendResult {
+    let lower = response.to_lowercase();
+
+    if lower.trim() == "queued" {
+        SendResult::Queued
+    } else if lower.contains("client not registered") {
+        SendResult::InvalidClientKey
+    } else if lower.contains("incorrect secret key") || lower.contains("no such telegram") || lower.contains("invalid tgid") {
+        SendResult::InvalidTemplate
+    } else if lower.contains("unknown nation") || lower.contains("nation not found") {
+        SendResult::NoSuchNation
+    } else if lower.contains("rate limit") || lower.contains("too many requests") {
+        SendResult::RateLimited(parse_retry_after(response))
+    } else {
+        SendResult::ServerError(response.trim().chars().take(200).collect())
+    }
+}
+
+/// The HTTP status NationStates responded with, if the request failed because of one.
+/// Caramel's `ApiError` carries the `reqwest::Error` as its source, so the status is looked up along the source chain.
+fn error_status(err: &(dyn Error + 'static)) -> Option<StatusCode> {
+    std::iter::successors(Some(err as &(dyn Error + 'static)), |err| (*err).source())
+        .find_map(|err| err.downcast_ref::<reqwest::Error>())
+        .and_then(reqwest::Error::status)
+}
+
+fn classify_send_error(err: &(dyn Error + 'static)) -> SendResult {
+    match error_status(err) {
+        Some(StatusCode::TOO_MANY_REQUESTS) => SendResult::RateLimited(parse_retry_after(&err.to_string())),
+        Some(StatusCode::NOT_FOUND) => SendResult::NoSuchNation,
+        Some(StatusCode::FORBIDDEN) => SendResult::InvalidClientKey,
+        _ => SendResult::ServerError(err.to_string()),
+    }
 }
 
 pub async fn send_telegram(
-    client: &Client, telegram: Telegram
-) -> Result<(), ApiError> {
-    let response = client.make_request_with_retry(vec![
+    client: &Client, telegram: &Telegram
+) -> SendResult {
+    let result = client.make_request_with_retry(vec![
             ("a", "sendTG"), 
             ("client", &telegram.client_key),
             ("tgid", &telegram.tgid),
             ("key", &telegram.tg_key),
             ("to", &telegram.nation)
-        ]).await?;
+        ]).await;
 
-    if response.contains("Client Not Registered For API") {
-        warn!("Error while sending telegram: Invalid client key!");
+    let result = match result {
+        Ok(response) => classify_send_response(&response),
+        Err(err) => classify_send_error(&err),
+    };
+
+    match &result {
+        SendResult::Queued => METRICS.telegrams_sent.inc(&telegram.tgid),
+        failure => METRICS.send_failures.inc(failure.kind()),
     }
 
-    return Ok(());
+    result
 }
 
 #[derive(Deserialize)]
@@ -52,16 +154,122 @@ struct CanRecruitRoot {
     pub can_recruit: String,
 }
 
+/// Asks the API whether a nation accepts recruitment telegrams. Returns None if the request failed.
 pub async fn can_telegram(
     client: &Client, nation: &str
-) -> bool {
-    if let Ok(response) = client.make_request(vec![
+) -> Option<bool> {
+    METRICS.can_telegram_requests.inc();
+
+    let response = client.make_request(vec![
         ("nation", nation), ("q", "tgcanrecruit")
+    ]).await.inspect_err(|err| warn!("Failed to check whether nation {} can be recruited: {}", nation, err)).ok()?;
+
+    quick_xml::de::from_str::<CanRecruitRoot>(&response).map(|v| v.can_recruit == "1").ok()
+}
+
+#[derive(Deserialize)]
+struct NationStatusRoot {
+    #[serde(rename = "REGION")]
+    pub region: String,
+    #[serde(rename = "UNSTATUS")]
+    pub wa_status: String,
+}
+
+/// What the API says about a nation right now.
+pub enum NationStatus {
+    /// The nation doesn't exist, e.g. because it ceased to exist.
+    Missing,
+    /// The nation exists, in `region` (canonicalized), and is or isn't a WA member or delegate.
+    Exists { region: String, wa: bool },
+}
+
+/// Looks up a nation's region and WA status. Returns None if the request failed.
+pub async fn query_nation_status(
+    client: &Client, nation: &str
+) -> Option<NationStatus> {
+    let response = match client.make_request(vec![
+        ("nation", nation), ("q", "region+wa")
     ]).await {
-        return quick_xml::de::from_str::<CanRecruitRoot>(&response).and_then(
-            |v| Ok(&v.can_recruit == "1")
-        ).unwrap_or(false);
+        Ok(response) => response,
+        Err(err) if error_status(&err) == Some(StatusCode::NOT_FOUND) => return Some(NationStatus::Missing),
+        Err(err) => {
+            warn!("Failed to look up nation {}: {}", nation, err);
+            return None;
+        }
+    };
+
+    let status = quick_xml::de::from_str::<NationStatusRoot>(&response).inspect_err(
+        |err| warn!("Invalid XML from nation {} API request: {}", nation, err)
+    ).ok()?;
+
+    Some(NationStatus::Exists {
+        region: canonicalize_region(&status.region),
+        wa: status.wa_status != "Non-member",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_send_responses() {
+        let cases = [
+            ("queued\n", SendResult::Queued),
+            ("Telegram not queued", SendResult::ServerError("Telegram not queued".into())),
+            ("Client Not Registered For API", SendResult::InvalidClientKey),
+            ("Incorrect Secret Key", SendResult::InvalidTemplate),
+            ("No Such Telegram Template", SendResult::InvalidTemplate),
+            ("Invalid TGID", SendResult::InvalidTemplate),
+            ("Unknown nation: \"testlandia_prime\"", SendResult::NoSuchNation),
+            ("Nation not found", SendResult::NoSuchNation),
+            ("API Rate Limit Exceeded. Retry-After: 180", SendResult::RateLimited(Some(Duration::from_secs(180)))),
+            ("Too Many Requests", SendResult::RateLimited(None)),
+            (
+                "<html><body><h1>503 Service Unavailable</h1></body></html>\n",
+                SendResult::ServerError("<html><body><h1>503 Service Unavailable</h1></body></html>".into()),
+            ),
+        ];
+
+        for (response, expected) in cases {
+            assert_eq!(classify_send_response(response), expected, "response {:?}", response);
+        }
+    }
+
+    #[test]
+    fn truncates_unknown_responses() {
+        let response = "x".repeat(500);
+        assert_eq!(classify_send_response(&response), SendResult::ServerError("x".repeat(200)));
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(reqwest::Error);
+
+    impl std::fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "request failed: {}", self.0)
+        }
     }
 
-    return false;
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn status_error(status: u16) -> Wrapped {
+        let response = http::Response::builder().status(status).body("").unwrap();
+        Wrapped(reqwest::Response::from(response).error_for_status().unwrap_err())
+    }
+
+    #[test]
+    fn classifies_send_errors_by_status() {
+        assert_eq!(error_status(&status_error(404)), Some(StatusCode::NOT_FOUND));
+        assert_eq!(error_status(&std::fmt::Error), None);
+
+        assert_eq!(classify_send_error(&status_error(404)), SendResult::NoSuchNation);
+        assert_eq!(classify_send_error(&status_error(403)), SendResult::InvalidClientKey);
+        assert!(matches!(classify_send_error(&status_error(429)), SendResult::RateLimited(_)));
+        assert!(matches!(classify_send_error(&status_error(500)), SendResult::ServerError(_)));
+    }
 }
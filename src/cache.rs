@@ -1,52 +1,76 @@
-use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
 
 use caramel::ns::api::Client;
-use regex::{Error, Regex};
 use tokio::sync::{RwLock, mpsc};
 
-pub struct RegexCache {
-    map: HashMap<String, Regex>
-}
-
-impl RegexCache {
-    pub fn new() -> Self {
-        Self { map: HashMap::new() }
-    }
+use crate::api::can_telegram;
 
-    pub fn get_regex(&mut self, pattern: &str) -> Result<Regex, Error> {
-        Ok(self.map.entry(pattern.to_string()).or_insert(Regex::new(pattern)?).clone())
-    }
-}
+/// How long a nation's recruitment status is trusted before asking the API again.
+const RECRUIT_STATUS_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Expired recruitment statuses are cleared out once the cache grows past this many nations.
+const RECRUIT_STATUS_PRUNE_SIZE: usize = 10_000;
 
 pub struct Cache {
-    pub regex: RwLock<RegexCache>,
     pub wa_nations: RwLock<HashSet<String>>,
     pub wa_signal: mpsc::Sender<()>,
+    /// Whether each nation accepts recruitment telegrams, and when that was last checked.
+    pub recruit_status: RwLock<HashMap<String, (bool, Instant)>>,
     pub client: Arc<Client>
 }
 
+impl Cache {
+    /// The cached recruitment status of a nation, if it was checked recently. Never makes an API request.
+    pub async fn cached_can_recruit(&self, nation: &str) -> Option<bool> {
+        self.recruit_status.read().await.get(nation)
+            .filter(|(_, checked_at)| checked_at.elapsed() < RECRUIT_STATUS_TTL)
+            .map(|(can_recruit, _)| *can_recruit)
+    }
+
+    /// Whether a nation accepts recruitment telegrams, asking the API if it isn't cached.
+    /// Returns None if the nation wasn't cached and the API request failed.
+    pub async fn can_recruit(&self, nation: &str) -> Option<bool> {
+        if let Some(can_recruit) = self.cached_can_recruit(nation).await {
+            return Some(can_recruit);
+        }
+
+        let can_recruit = can_telegram(&self.client, nation).await?;
+
+        let mut recruit_status = self.recruit_status.write().await;
+        if recruit_status.len() >= RECRUIT_STATUS_PRUNE_SIZE {
+            recruit_status.retain(|_, (_, checked_at)| checked_at.elapsed() < RECRUIT_STATUS_TTL);
+        }
+        recruit_status.insert(nation.to_string(), (can_recruit, Instant::now()));
+
+        Some(can_recruit)
+    }
+}
+
 pub fn spawn_wa_worker(
     client: Arc<Client>,
 ) -> Arc<Cache> {
     let (send, mut recv) = mpsc::channel::<()>(100);
 
     let cache = Arc::new(Cache {
-        regex: RwLock::new(RegexCache::new()),
         wa_nations: RwLock::new(HashSet::new()),
         wa_signal: send,
+        recruit_status: RwLock::new(HashMap::new()),
         client: client.clone()
     });
 
     let cache_clone = cache.clone();
-    let _ = tokio::spawn(async move {
-        while let Some(_) = recv.recv().await {
+    tokio::spawn(async move {
+        while recv.recv().await.is_some() {
             loop {
-                let mut wa_nations = cache_clone.wa_nations.write().await;
+                // Fill a new set and swap it in, so events aren't held up by the lock for the whole request
+                let mut members = HashSet::new();
 
-                if let Err(_) = crate::api::query_wa_nations(&client, &mut wa_nations).await {
-                    drop(wa_nations);
+                if crate::api::query_wa_nations(&client, &mut members).await.is_err() {
                     tokio::time::sleep(Duration::from_secs(120)).await; // Try again after 2 minutes
                 } else {
+                    // An unparseable response leaves the set empty, so keep the old list then
+                    if !members.is_empty() {
+                        *cache_clone.wa_nations.write().await = members;
+                    }
                     break;
                 }
             }
@@ -1,15 +1,51 @@
-use log::{error, warn};
-use serde::Deserialize;
-use std::{collections::HashMap, fs, process::exit};
-use toml::{Table, Value};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs, sync::Arc};
+use tokio::sync::RwLock;
+use toml::{Table, Value, value::{Datetime, Offset}};
 
-#[derive(Debug, Deserialize)]
+use crate::expr::compile_expression;
+use crate::metrics::{METRICS, RuleCounter};
+use crate::revalidate::Revalidation;
+use crate::schedule::{DailyWindow, MINUTES_PER_DAY, Schedule, parse_hours, parse_time_of_day, parse_weekday, unix_from_utc};
+use crate::rules::{ArgList, Condition, NationPredicate, compile_nation_arg, compile_regex, compile_region_arg, event_name};
+
+const DEFAULT_STATE_PATH: &str = "data/crystal-state.json";
+/// Longest send history that can be kept, about ten years.
+const MAX_HISTORY_DAYS: u64 = 3650;
+/// Longest conversion window, a year.
+const MAX_CONVERSION_WINDOW_HOURS: u64 = 365 * 24;
+/// Longest a queue's telegrams can be kept before expiring, a year.
+const MAX_QUEUE_AGE_MINUTES: u64 = 365 * 24 * 60;
+/// Margins around updates must be shorter than this, in minutes, so the avoided window is less than a day.
+const MAX_UPDATE_MARGIN: u32 = 12 * 60;
+
+/// A rule, with its lists, `text` pattern and `match` expression compiled at load time.
+#[derive(Debug)]
 pub struct Rule {
-    pub event: Vec<String>,
-    pub regions: Vec<String>,
-    pub nations: Vec<String>,
+    /// All of the rule's conditions, which must match for the rule to match.
+    pub condition: Condition,
     pub queue: String,
-    pub templates: Vec<String>
+    pub templates: Vec<WeightedTemplate>,
+    /// Region this rule recruits for. Nations moving there after being telegrammed count as conversions.
+    pub target_region: Option<String>,
+    /// When the rule is active. Outside of it, events are not checked against the rule at all.
+    pub schedule: Option<Schedule>,
+    /// Rules with a higher priority are checked first.
+    pub priority: i64,
+    /// Whether to keep checking later rules after this one matches, instead of stopping.
+    pub continue_matching: bool,
+    /// Conditions checked again right before this rule's telegrams are sent.
+    pub revalidate: Vec<Revalidation>,
+    /// Evaluation and match counts, only shared with `/metrics` once the config goes live.
+    pub counter: Arc<RuleCounter>,
+}
+
+/// A template a rule can pick from. Templates with a higher weight are picked proportionally more often.
+#[derive(Debug)]
+pub struct WeightedTemplate {
+    pub name: String,
+    pub weight: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,151 +55,821 @@ pub struct TemplateConfig {
     pub client_key: String,
 }
 
+/// The order a queue sends its telegrams in. Telegrams that are retried keep their place at the head of the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueOrder {
+    /// Newest telegram is sent first, which suits recruitment, where the newest nations are the most likely to join.
+    Lifo,
+    /// Oldest telegram is sent first, which suits regional mass telegrams that should go out in the order requested.
+    Fifo,
+}
+
+/// Which telegrams a full queue drops to stay within its maximum length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// Drop the telegrams that have been queued the longest.
+    DropOldest,
+    /// Drop the most recently queued telegrams, so a full queue turns new telegrams away.
+    DropNewest,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub ephemeral: bool,
+    pub recruitment: bool,
+    /// Queues with a higher priority are sent from first.
+    pub priority: i64,
+    pub max_length: Option<usize>,
+    pub eviction: Eviction,
+    /// Telegrams queued longer than this many seconds are dropped instead of sent.
+    pub max_age: Option<u64>,
+    pub order: QueueOrder,
+    /// API client keys allowed to send this queue's telegrams.
+    /// If empty, each telegram is sent with the client key of its template.
+    pub clients: Vec<String>,
+    /// Whether the queue starts out paused. Only applied at startup, use the API to pause and resume at runtime.
+    pub paused: bool,
+    /// When the queue sends telegrams. Outside of it, telegrams are still queued but not sent.
+    pub schedule: Option<Schedule>,
+    /// Conditions checked again right before this queue's telegrams are sent.
+    pub revalidate: Vec<Revalidation>,
+}
+
 #[derive(Debug)]
 pub struct InputConfig {
     pub exchange_name: String,
 }
 
+#[derive(Debug)]
+pub struct PersistenceConfig {
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct SenderConfig {
+    /// Whether sending starts out paused. Only applied at startup, use the API to pause and resume at runtime.
+    pub paused: bool,
+    /// How many days after sending a template to a nation it's blocked from being queued for them again.
+    /// Zero disables the send history.
+    pub history_days: u64,
+    /// How many times a telegram is retried after a server error before it's moved to the dead letters.
+    pub max_retries: u32,
+    /// Delay before the first retry of a failed telegram, in seconds. Doubles with every attempt.
+    pub retry_backoff: u64,
+}
+
+/// When to remove a nation's telegrams from every queue, because it can't or shouldn't receive them anymore.
+#[derive(Debug)]
+pub struct PurgeConfig {
+    /// Purge nations that cease to exist.
+    pub on_cte: bool,
+    /// Purge nations that move to any of these regions, e.g. because they're already ours.
+    pub regions: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct TrackingConfig {
+    /// How many hours after being telegrammed a nation moving to a rule's target region counts as a conversion.
+    pub window_hours: u64,
+}
+
+/// Daily NationStates update times, which rule and queue schedules can avoid.
+/// These are fixed in UTC, so they need adjusting when daylight saving time starts or ends.
+/// Only used while parsing, schedules keep their own copy of the windows they avoid.
+#[derive(Debug)]
+struct UpdatesConfig {
+    pub major: DailyWindow,
+    pub minor: DailyWindow,
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub input: InputConfig,
+    pub persistence: PersistenceConfig,
+    pub sender: SenderConfig,
+    pub tracking: TrackingConfig,
+    pub purge: PurgeConfig,
+    pub queues: Vec<(String, QueueConfig)>,
     pub templates: HashMap<String, TemplateConfig>,
     pub rules: Vec<(String, Rule)>,
 }
 
-fn parse_template(name: &str, table: &Table) -> Option<TemplateConfig> {
+impl Config {
+    /// Hooks every rule up to its metrics. Only done for configs that go live, so configs that are just checked
+    /// or fail to reload don't leave metrics behind.
+    pub fn register_metrics(&mut self) {
+        for (name, rule) in &mut self.rules {
+            rule.counter = METRICS.rules.register(name);
+        }
+    }
+}
+
+/// The active config, swapped out as a whole when the config file is reloaded.
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+/// A single problem found in the config, with the dotted TOML path it was found at.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub location: String,
+    pub message: String,
+}
+
+/// Every problem found while validating a config file.
+#[derive(Debug, Default)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) found in config:", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  {}: {}", error.location, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl ConfigErrors {
+    fn add(&mut self, location: &str, message: impl Into<String>) {
+        self.0.push(ConfigError { location: location.to_string(), message: message.into() });
+    }
+
+    fn string(&mut self, location: &str, value: &Value) -> Option<String> {
+        match value {
+            Value::String(v) => Some(v.clone()),
+            _ => { self.add(location, format!("expected a string, found {}", value.type_str())); None }
+        }
+    }
+
+    fn boolean(&mut self, location: &str, value: &Value) -> Option<bool> {
+        match value {
+            Value::Boolean(v) => Some(*v),
+            _ => { self.add(location, format!("expected a boolean, found {}", value.type_str())); None }
+        }
+    }
+
+    fn integer(&mut self, location: &str, value: &Value) -> Option<i64> {
+        match value {
+            Value::Integer(v) => Some(*v),
+            _ => { self.add(location, format!("expected an integer, found {}", value.type_str())); None }
+        }
+    }
+
+    fn table<'a>(&mut self, location: &str, value: &'a Value) -> Option<&'a Table> {
+        match value {
+            Value::Table(v) => Some(v),
+            _ => { self.add(location, format!("expected a table, found {}", value.type_str())); None }
+        }
+    }
+
+    fn string_array(&mut self, location: &str, value: &Value) -> Option<Vec<String>> {
+        let Value::Array(array) = value else {
+            self.add(location, format!("expected an array of strings, found {}", value.type_str()));
+            return None;
+        };
+
+        let mut result = Vec::new();
+        for (index, item) in array.iter().enumerate() {
+            if let Some(item) = self.string(&format!("{}[{}]", location, index), item) {
+                result.push(item);
+            }
+        }
+
+        Some(result)
+    }
+
+    fn unknown_key(&mut self, location: &str) {
+        self.add(location, "unrecognized key");
+    }
+}
+
+/// Region names as NationStates and Akari write them: lowercase, with underscores instead of spaces.
+pub fn canonicalize_region(region: &str) -> String {
+    region.trim().to_lowercase().replace(' ', "_")
+}
+
+fn default_queues() -> Vec<(String, QueueConfig)> {
+    vec![
+        ("recruit-permanent".into(), QueueConfig { 
+            ephemeral: false, recruitment: true, priority: 30, max_length: None, eviction: Eviction::DropOldest, max_age: None, order: QueueOrder::Lifo, clients: Vec::new(), paused: false,
+            schedule: None, revalidate: Vec::new(),
+        }),
+        ("recruit-ephemeral".into(), QueueConfig { 
+            ephemeral: true, recruitment: true, priority: 20, max_length: None, eviction: Eviction::DropOldest, max_age: None, order: QueueOrder::Lifo, clients: Vec::new(), paused: false,
+            schedule: None, revalidate: Vec::new(),
+        }),
+        ("regional".into(), QueueConfig { 
+            ephemeral: false, recruitment: false, priority: 10, max_length: None, eviction: Eviction::DropOldest, max_age: None, order: QueueOrder::Fifo, clients: Vec::new(), paused: false,
+            schedule: None, revalidate: Vec::new(),
+        }),
+    ]
+}
+
+/// Converts a TOML date or date-time to a Unix timestamp. Dates without a time are taken as the start of
+/// the day, or the end of it if `end_of_day` is set. Date-times without an offset are taken as UTC.
+fn parse_timestamp(location: &str, value: &Value, end_of_day: bool, errors: &mut ConfigErrors) -> Option<u64> {
+    let Value::Datetime(Datetime { date: Some(date), time, offset }) = value else {
+        errors.add(location, format!("expected a date like 2026-01-31, found {}", value.type_str()));
+        return None;
+    };
+
+    let seconds_of_day = match time {
+        Some(time) => time.hour as u64 * 3600 + time.minute as u64 * 60 + time.second as u64,
+        None if end_of_day => 24 * 60 * 60,
+        None => 0,
+    };
+
+    let timestamp = unix_from_utc(date.year as i64, date.month as u32, date.day as u32, seconds_of_day);
+    let offset = match offset {
+        Some(Offset::Custom { minutes }) => *minutes as i64 * 60,
+        _ => 0,
+    };
+
+    match timestamp.and_then(|timestamp| timestamp.checked_add_signed(-offset)) {
+        Some(timestamp) => Some(timestamp),
+        None => { errors.add(location, "date is out of range"); None },
+    }
+}
+
+fn parse_schedule(location: &str, value: &Value, updates: &UpdatesConfig, errors: &mut ConfigErrors) -> Option<Schedule> {
+    let table = errors.table(location, value)?;
+    let mut result = Schedule::default();
+
+    for (key, value) in table.iter() {
+        let location = format!("{}.{}", location, key);
+
+        match key.as_str() {
+            "hours" => if let Some(v) = errors.string(&location, value) {
+                match parse_hours(&v) {
+                    Some(hours) => result.hours = Some(hours),
+                    None => errors.add(&location, format!("invalid hours '{}', expected a range like '08:00-20:00'", v)),
+                }
+            },
+            "days" => if let Some(v) = errors.string_array(&location, value) {
+                let mut days = 0;
+                for (index, day) in v.iter().enumerate() {
+                    match parse_weekday(day) {
+                        Some(day) => days |= day,
+                        None => errors.add(&format!("{}[{}]", location, index), format!("unknown day '{}'", day)),
+                    }
+                }
+                result.days = Some(days);
+            },
+            "start" => result.start = parse_timestamp(&location, value, false, errors),
+            "end" => result.end = parse_timestamp(&location, value, true, errors),
+            "avoid_major_update" | "avoid_minor_update" => if let Some(v) = errors.integer(&location, value) {
+                let update = if key == "avoid_major_update" { updates.major } else { updates.minor };
+
+                match u32::try_from(v) {
+                    Ok(margin) if margin < MAX_UPDATE_MARGIN => result.avoid.push(update.widen(margin)),
+                    Ok(_) => errors.add(&location, format!("must be less than {} minutes", MAX_UPDATE_MARGIN)),
+                    Err(_) => errors.add(&location, "must not be negative"),
+                }
+            },
+            _ => errors.unknown_key(&location),
+        }
+    }
+
+    if let (Some(start), Some(end)) = (result.start, result.end) && end <= start {
+        errors.add(location, "'end' must be after 'start'");
+    }
+
+    Some(result)
+}
+
+fn parse_revalidations(location: &str, value: &Value, errors: &mut ConfigErrors) -> Vec<Revalidation> {
+    let mut result = Vec::new();
+
+    for (index, check) in errors.string_array(location, value).unwrap_or_default().iter().enumerate() {
+        match Revalidation::parse(check) {
+            Ok(check) => result.push(check),
+            Err(err) => errors.add(&format!("{}[{}]", location, index), err),
+        }
+    }
+
+    result
+}
+
+fn parse_queue(name: &str, table: &Table, updates: &UpdatesConfig, errors: &mut ConfigErrors) -> QueueConfig {
+    let mut result = QueueConfig { 
+        ephemeral: false, recruitment: false, priority: 0, max_length: None, eviction: Eviction::DropOldest, max_age: None, order: QueueOrder::Lifo, clients: Vec::new(), paused: false,
+        schedule: None, revalidate: Vec::new(),
+    };
+
+    for (key, value) in table.iter() {
+        let location = format!("queues.{}.{}", name, key);
+
+        match key.as_str() {
+            "ephemeral" => if let Some(v) = errors.boolean(&location, value) {
+                result.ephemeral = v;
+            },
+            "recruitment" => if let Some(v) = errors.boolean(&location, value) {
+                result.recruitment = v;
+            },
+            "priority" => if let Some(v) = errors.integer(&location, value) {
+                result.priority = v;
+            },
+            "max_length" => if let Some(v) = errors.integer(&location, value) {
+                if v > 0 {
+                    result.max_length = Some(v as usize);
+                } else {
+                    errors.add(&location, "must be greater than zero");
+                }
+            },
+            "eviction" => if let Some(v) = errors.string(&location, value) {
+                match v.to_lowercase().as_str() {
+                    "drop_oldest" => result.eviction = Eviction::DropOldest,
+                    "drop_newest" => result.eviction = Eviction::DropNewest,
+                    _ => errors.add(&location, format!("invalid eviction '{}', expected 'drop_oldest' or 'drop_newest'", v)),
+                }
+            },
+            "max_age_minutes" => if let Some(v) = errors.integer(&location, value) {
+                match u64::try_from(v) {
+                    Ok(v) if v > 0 && v <= MAX_QUEUE_AGE_MINUTES => result.max_age = Some(v * 60),
+                    _ => errors.add(&location, format!("must be between 1 and {}", MAX_QUEUE_AGE_MINUTES)),
+                }
+            },
+            "clients" => if let Some(v) = errors.string_array(&location, value) {
+                result.clients = v;
+            },
+            "paused" => if let Some(v) = errors.boolean(&location, value) {
+                result.paused = v;
+            },
+            "order" => if let Some(v) = errors.string(&location, value) {
+                match v.to_lowercase().as_str() {
+                    "lifo" => result.order = QueueOrder::Lifo,
+                    "fifo" => result.order = QueueOrder::Fifo,
+                    _ => errors.add(&location, format!("invalid order '{}', expected 'lifo' or 'fifo'", v)),
+                }
+            },
+            "schedule" => result.schedule = parse_schedule(&location, value, updates, errors),
+            "revalidate" => result.revalidate = parse_revalidations(&location, value, errors),
+            _ => errors.unknown_key(&location),
+        }
+    }
+
+    result
+}
+
+fn parse_queue_map(table: &Table, updates: &UpdatesConfig, errors: &mut ConfigErrors) -> Vec<(String, QueueConfig)> {
+    let mut result = Vec::new();
+
+    for (key, value) in table.iter() {
+        if let Some(t) = errors.table(&format!("queues.{}", key), value) {
+            result.push((key.clone(), parse_queue(key, t, updates, errors)));
+        }
+    }
+
+    // Stable sort, so queues with equal priority keep a deterministic (alphabetical) order
+    result.sort_by_key(|(_, queue)| std::cmp::Reverse(queue.priority));
+    result
+}
+
+fn parse_template(name: &str, table: &Table, errors: &mut ConfigErrors) -> TemplateConfig {
     let mut result = TemplateConfig { tgid: "".into(), tg_key: "".into(), client_key: "".into() };
 
     for (key, value) in table.iter() {
-        if key == "tgid" && let toml::Value::String(v) = value {
-            result.tgid = v.clone();
-        } else if key == "tg_key" && let toml::Value::String(v) = value {
-            result.tg_key = v.clone();
-        } else if key == "client_key" && let toml::Value::String(v) = value {
-            result.client_key = v.clone();
-        } else {
-            warn!("Unrecognized config key {} in template {}", key, name);
-            return None;
+        let location = format!("templates.{}.{}", name, key);
+
+        match key.as_str() {
+            "tgid" => if let Some(v) = errors.string(&location, value) {
+                result.tgid = v;
+            },
+            "tg_key" => if let Some(v) = errors.string(&location, value) {
+                result.tg_key = v;
+            },
+            "client_key" => if let Some(v) = errors.string(&location, value) {
+                result.client_key = v;
+            },
+            _ => errors.unknown_key(&location),
         }
     }
 
-    if result.tgid.is_empty() || result.tg_key.is_empty() || result.client_key.is_empty() {
-        warn!("Template {} is missing fields", name);
-        None
-    } else {
-        Some(result)
+    for (key, value) in [("tgid", &result.tgid), ("tg_key", &result.tg_key), ("client_key", &result.client_key)] {
+        if value.is_empty() {
+            errors.add(&format!("templates.{}", name), format!("missing required key '{}'", key));
+        }
     }
+
+    result
 }
 
-fn parse_template_map(table: &Table) -> HashMap<String, TemplateConfig> {
+fn parse_template_map(table: &Table, errors: &mut ConfigErrors) -> HashMap<String, TemplateConfig> {
     let mut result = HashMap::new();
 
     for (key, value) in table.iter() {
-        if let toml::Value::Table(t) = value {
-            if let Some(template) = parse_template(key, t) {
-                result.insert(key.clone(), template);
-            } else {
-                warn!("Couldn't parse template '{}'", key);
-            }
+        if let Some(t) = errors.table(&format!("templates.{}", key), value) {
+            result.insert(key.clone(), parse_template(key, t, errors));
         }
     }
 
     result
 }
 
-fn convert_toml_array_to_string_vec(array: &Vec<Value>) -> Vec<String> {
-    array.iter().flat_map(
-        |v| v.as_str().and_then(
-            |v| Some(v.to_string())
-        )
-    ).collect()
+/// Parses a rule's `templates` list, where each entry is either a template name
+/// or a table like `{ template = "name", weight = 2 }`.
+fn parse_weighted_templates(location: &str, value: &Value, errors: &mut ConfigErrors) -> Vec<WeightedTemplate> {
+    let Value::Array(array) = value else {
+        errors.add(location, format!("expected an array, found {}", value.type_str()));
+        return Vec::new();
+    };
+
+    let mut result = Vec::new();
+    for (index, item) in array.iter().enumerate() {
+        let location = format!("{}[{}]", location, index);
+
+        match item {
+            Value::String(name) => result.push(WeightedTemplate { name: name.clone(), weight: 1 }),
+            Value::Table(table) => {
+                let mut template = WeightedTemplate { name: "".into(), weight: 1 };
+
+                for (key, value) in table.iter() {
+                    let location = format!("{}.{}", location, key);
+
+                    match key.as_str() {
+                        "template" => if let Some(v) = errors.string(&location, value) {
+                            template.name = v;
+                        },
+                        "weight" => if let Some(v) = errors.integer(&location, value) {
+                            match u32::try_from(v) {
+                                Ok(v) if v > 0 => template.weight = v,
+                                _ => errors.add(&location, "must be a positive integer"),
+                            }
+                        },
+                        _ => errors.unknown_key(&location),
+                    }
+                }
+
+                if template.name.is_empty() {
+                    errors.add(&location, "missing required key 'template'");
+                } else {
+                    result.push(template);
+                }
+            },
+            _ => errors.add(&location, format!("expected a string or a table, found {}", item.type_str())),
+        }
+    }
+
+    result
 }
 
-fn parse_rule(table: &Table) -> Rule {
+fn parse_rule(name: &str, table: &Table, updates: &UpdatesConfig, errors: &mut ConfigErrors) -> Rule {
+    let mut events = Vec::new();
+    let mut regions = ArgList::new();
+    let mut nations = ArgList::new();
+    let mut receptors = ArgList::new();
+    let mut other_regions = ArgList::new();
+    let mut text = None;
+    let mut expression = None;
+
     let mut result = Rule { 
-        event: Vec::new(),
-        regions: Vec::new(),
-        nations: Vec::new(),
+        condition: Condition::And(Vec::new()),
         queue: "".into(),
         templates: Vec::new(),
+        target_region: None,
+        schedule: None,
+        priority: 0,
+        continue_matching: false,
+        revalidate: Vec::new(),
+        counter: Arc::default(),
     };
 
-    if let Some(toml::Value::Array(s)) = table.get("event") {
-        result.event = convert_toml_array_to_string_vec(s);
+    for (key, value) in table.iter() {
+        let location = format!("rules.{}.{}", name, key);
+
+        match key.as_str() {
+            "event" => if let Some(v) = errors.string_array(&location, value) {
+                for (index, event) in v.iter().enumerate() {
+                    match event_name(event) {
+                        Some(event) => events.push(event),
+                        None => errors.add(&format!("{}[{}]", location, index), format!("unknown event '{}'", event)),
+                    }
+                }
+            },
+            "regions" => if let Some(v) = errors.string_array(&location, value) {
+                for (index, arg) in v.iter().enumerate() {
+                    match compile_region_arg(arg) {
+                        Ok(arg) => regions.push(arg),
+                        Err(err) => errors.add(&format!("{}[{}]", location, index), err),
+                    }
+                }
+            },
+            "nations" => if let Some(v) = errors.string_array(&location, value) {
+                for (index, arg) in v.iter().enumerate() {
+                    match compile_nation_arg(arg) {
+                        Ok(arg) => nations.push(arg),
+                        Err(err) => errors.add(&format!("{}[{}]", location, index), err),
+                    }
+                }
+            },
+            "receptors" => if let Some(v) = errors.string_array(&location, value) {
+                for (index, arg) in v.iter().enumerate() {
+                    match compile_nation_arg(arg) {
+                        Ok(arg) => receptors.push(arg),
+                        Err(err) => errors.add(&format!("{}[{}]", location, index), err),
+                    }
+                }
+            },
+            "other_regions" => if let Some(v) = errors.string_array(&location, value) {
+                for (index, arg) in v.iter().enumerate() {
+                    match compile_region_arg(arg) {
+                        Ok(arg) => other_regions.push(arg),
+                        Err(err) => errors.add(&format!("{}[{}]", location, index), err),
+                    }
+                }
+            },
+            "text" => if let Some(v) = errors.string(&location, value) {
+                match compile_regex(&v) {
+                    Ok(regex) => text = Some(regex),
+                    Err(err) => errors.add(&location, err),
+                }
+            },
+            "match" => if let Some(v) = errors.string(&location, value) {
+                match compile_expression(&v) {
+                    Ok(condition) => expression = Some(condition),
+                    Err(err) => errors.add(&location, err),
+                }
+            },
+            "queue" => if let Some(v) = errors.string(&location, value) {
+                result.queue = v;
+            },
+            "templates" => result.templates = parse_weighted_templates(&location, value, errors),
+            "target_region" => if let Some(v) = errors.string(&location, value) {
+                result.target_region = Some(canonicalize_region(&v));
+            },
+            "schedule" => result.schedule = parse_schedule(&location, value, updates, errors),
+            "priority" => if let Some(v) = errors.integer(&location, value) {
+                result.priority = v;
+            },
+            "continue" => if let Some(v) = errors.boolean(&location, value) {
+                result.continue_matching = v;
+            },
+            "revalidate" => result.revalidate = parse_revalidations(&location, value, errors),
+            _ => errors.unknown_key(&location),
+        }
     }
 
-    if let Some(toml::Value::Array(s)) = table.get("regions") {
-        result.regions = convert_toml_array_to_string_vec(s);
+    let location = format!("rules.{}", name);
+    let mut required = vec![("templates", result.templates.is_empty())];
+
+    // A `match` expression can stand in for the lists, which are then optional
+    if !table.contains_key("match") {
+        required.extend([
+            ("event", events.is_empty()), ("regions", regions.is_empty()), ("nations", nations.is_empty()),
+        ]);
     }
 
-    if let Some(toml::Value::Array(s)) = table.get("nations") {
-        result.nations = convert_toml_array_to_string_vec(s);
+    for (key, empty) in required {
+        if empty {
+            errors.add(&location, format!("'{}' is missing or empty, so this rule can never send anything", key));
+        }
     }
-    
-    if let Some(toml::Value::String(s)) = table.get("queue") {
-        result.queue = s.clone();
+
+    // Lists come first and regions before nations, since most events are ruled out by their event and region
+    let mut conditions = Vec::new();
+    if !events.is_empty() { conditions.push(Condition::Event(events)); }
+    if !regions.is_empty() { conditions.push(Condition::Regions(regions)); }
+    if !other_regions.is_empty() { conditions.push(Condition::OtherRegions(other_regions)); }
+    if let Some(text) = text { conditions.push(Condition::Text(text)); }
+    if !nations.is_empty() { conditions.push(Condition::Nations(nations)); }
+    if !receptors.is_empty() { conditions.push(Condition::Receptors(receptors)); }
+    conditions.extend(expression);
+    result.condition = Condition::And(conditions);
+
+    // Recruitment status is only checked against the cache when matching, so rules that exclude nations with
+    // recruitment disabled are checked for real before sending. Rules that match `$recruitment_disabled`
+    // only go by the cache, and anything else is left to `revalidate`.
+    let excludes_unrecruitable = result.condition.excludes_nations(
+        &|predicate| matches!(predicate, NationPredicate::RecruitmentDisabled)
+    );
+    if excludes_unrecruitable && !result.revalidate.contains(&Revalidation::Recruitable) {
+        result.revalidate.push(Revalidation::Recruitable);
     }
 
-    if let Some(toml::Value::Array(s)) = table.get("templates") {
-        result.templates = convert_toml_array_to_string_vec(s);
+    if result.queue.is_empty() {
+        errors.add(&location, "missing required key 'queue'");
     }
 
     result
 }
 
-fn parse_rules(table: &Table) -> Vec<(String, Rule)> {
+fn parse_rules(table: &Table, updates: &UpdatesConfig, errors: &mut ConfigErrors) -> Vec<(String, Rule)> {
     let mut result = Vec::new();
 
     for (key, value) in table.iter() {
-        if let toml::Value::Table(v) = value {
-            result.push((key.clone(), parse_rule(v)));
+        if let Some(v) = errors.table(&format!("rules.{}", key), value) {
+            result.push((key.clone(), parse_rule(key, v, updates, errors)));
         }
     }
 
+    // Ties are broken by name, so the order never depends on how the table was written
+    result.sort_by(|(a_name, a), (b_name, b)| b.priority.cmp(&a.priority).then_with(|| a_name.cmp(b_name)));
     result
 }
 
+fn check_references(config: &Config, errors: &mut ConfigErrors) {
+    for (rule_name, rule) in &config.rules {
+        if !rule.queue.is_empty() && !config.queues.iter().any(|(name, _)| name == &rule.queue) {
+            errors.add(&format!("rules.{}.queue", rule_name), format!("queue '{}' doesn't exist", rule.queue));
+        }
+
+        for (index, template) in rule.templates.iter().enumerate() {
+            if !config.templates.contains_key(&template.name) {
+                errors.add(
+                    &format!("rules.{}.templates[{}]", rule_name, index), 
+                    format!("template '{}' doesn't exist", template.name)
+                );
+            }
+        }
+    }
+}
+
+/// Parses and validates the config file at `path`.
+/// Fails with a [`ConfigErrors`] listing every problem found if the config is invalid.
 pub fn parse_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
     let contents = fs::read_to_string(path)?;
-    let table: toml::Table = toml::from_str(&contents.as_str())?;
+    let table: toml::Table = toml::from_str(contents.as_str())?;
+    let mut errors = ConfigErrors::default();
+
+    let mut input = InputConfig { exchange_name: "".into() };
+    match table.get("input") {
+        Some(value) => if let Some(t) = errors.table("input", value) {
+            for (key, value) in t.iter() {
+                let location = format!("input.{}", key);
 
-    let input: InputConfig = match table.get("input") {
-        Some(toml::Value::Table(t)) => {
-            let exchange_name = match t.get("exchange_name") {
-                Some(toml::Value::String(s)) => s.clone(),
-                _ => {
-                    error!("Config is missing required 'input.exchange_name' value!");
-                    exit(1);
+                match key.as_str() {
+                    "exchange_name" => if let Some(v) = errors.string(&location, value) {
+                        input.exchange_name = v;
+                    },
+                    _ => errors.unknown_key(&location),
                 }
-            };
+            }
 
-            InputConfig { exchange_name }
+            if input.exchange_name.is_empty() {
+                errors.add("input", "missing required key 'exchange_name'");
+            }
         },
-        _ => {
-            error!("Config is missing required 'input' section!");
-            exit(1);
+        None => errors.add("input", "missing required section"),
+    }
+
+    let mut persistence = PersistenceConfig { path: DEFAULT_STATE_PATH.into() };
+    if let Some(value) = table.get("persistence") && let Some(t) = errors.table("persistence", value) {
+        for (key, value) in t.iter() {
+            let location = format!("persistence.{}", key);
+
+            match key.as_str() {
+                "path" => if let Some(v) = errors.string(&location, value) {
+                    persistence.path = v;
+                },
+                _ => errors.unknown_key(&location),
+            }
+        }
+    }
+
+    let mut sender = SenderConfig { paused: false, history_days: 0, max_retries: 5, retry_backoff: 60 };
+    if let Some(value) = table.get("sender") && let Some(t) = errors.table("sender", value) {
+        for (key, value) in t.iter() {
+            let location = format!("sender.{}", key);
+
+            match key.as_str() {
+                "paused" => if let Some(v) = errors.boolean(&location, value) {
+                    sender.paused = v;
+                },
+                "history_days" => if let Some(v) = errors.integer(&location, value) {
+                    match u64::try_from(v) {
+                        Ok(v) if v <= MAX_HISTORY_DAYS => sender.history_days = v,
+                        Ok(_) => errors.add(&location, format!("must be at most {}", MAX_HISTORY_DAYS)),
+                        Err(_) => errors.add(&location, "must not be negative"),
+                    }
+                },
+                "max_retries" => if let Some(v) = errors.integer(&location, value) {
+                    match u32::try_from(v) {
+                        Ok(v) => sender.max_retries = v,
+                        Err(_) => errors.add(&location, "must not be negative"),
+                    }
+                },
+                "retry_backoff" => if let Some(v) = errors.integer(&location, value) {
+                    if v > 0 {
+                        sender.retry_backoff = v as u64;
+                    } else {
+                        errors.add(&location, "must be greater than zero");
+                    }
+                },
+                _ => errors.unknown_key(&location),
+            }
+        }
+    }
+
+    let mut tracking = TrackingConfig { window_hours: 7 * 24 };
+    if let Some(value) = table.get("tracking") && let Some(t) = errors.table("tracking", value) {
+        for (key, value) in t.iter() {
+            let location = format!("tracking.{}", key);
+
+            match key.as_str() {
+                "window_hours" => if let Some(v) = errors.integer(&location, value) {
+                    match u64::try_from(v) {
+                        Ok(v) if v <= MAX_CONVERSION_WINDOW_HOURS => tracking.window_hours = v,
+                        Ok(_) => errors.add(&location, format!("must be at most {}", MAX_CONVERSION_WINDOW_HOURS)),
+                        Err(_) => errors.add(&location, "must not be negative"),
+                    }
+                },
+                _ => errors.unknown_key(&location),
+            }
+        }
+    }
+
+    let mut purge = PurgeConfig { on_cte: true, regions: Vec::new() };
+    if let Some(value) = table.get("purge") && let Some(t) = errors.table("purge", value) {
+        for (key, value) in t.iter() {
+            let location = format!("purge.{}", key);
+
+            match key.as_str() {
+                "on_cte" => if let Some(v) = errors.boolean(&location, value) {
+                    purge.on_cte = v;
+                },
+                "regions" => if let Some(v) = errors.string_array(&location, value) {
+                    purge.regions = v.iter().map(|region| canonicalize_region(region)).collect();
+                },
+                _ => errors.unknown_key(&location),
+            }
+        }
+    }
+
+    let mut updates = UpdatesConfig {
+        major: DailyWindow { start: 4 * 60, length: 90 },
+        minor: DailyWindow { start: 16 * 60, length: 60 },
+    };
+    if let Some(value) = table.get("updates") && let Some(t) = errors.table("updates", value) {
+        for (key, value) in t.iter() {
+            let location = format!("updates.{}", key);
+
+            match key.as_str() {
+                "major" | "minor" => if let Some(v) = errors.string(&location, value) {
+                    let update = if key == "major" { &mut updates.major } else { &mut updates.minor };
+
+                    match parse_time_of_day(&v) {
+                        Some(start) => update.start = start,
+                        None => errors.add(&location, format!("invalid time '{}', expected a UTC time like '04:00'", v)),
+                    }
+                },
+                "major_length" | "minor_length" => if let Some(v) = errors.integer(&location, value) {
+                    let update = if key == "major_length" { &mut updates.major } else { &mut updates.minor };
+
+                    match u32::try_from(v) {
+                        Ok(length) if length > 0 && length <= MINUTES_PER_DAY => update.length = length,
+                        _ => errors.add(&location, format!("must be between 1 and {}", MINUTES_PER_DAY)),
+                    }
+                },
+                _ => errors.unknown_key(&location),
+            }
         }
+    }
+
+    let queues = match table.get("queues") {
+        Some(value) => match errors.table("queues", value) {
+            Some(t) => parse_queue_map(t, &updates, &mut errors),
+            None => Vec::new(),
+        },
+        None => default_queues(),
     };
 
     let templates = match table.get("templates") {
-        Some(toml::Value::Table(t)) => {
-            parse_template_map(t)
+        Some(value) => match errors.table("templates", value) {
+            Some(t) => parse_template_map(t, &mut errors),
+            None => HashMap::new(),
         },
-        _ => {
+        None => {
             warn!("No templates specified in config!");
             HashMap::new()
         }
     };
 
     let rules = match table.get("rules") {
-        Some(toml::Value::Table(t)) => {
-            parse_rules(t)
+        Some(value) => match errors.table("rules", value) {
+            Some(t) => parse_rules(t, &updates, &mut errors),
+            None => Vec::new(),
         },
-        _ => {
+        None => {
             warn!("No rules specified in config!");
             Vec::new()
         }
     };
 
-    Ok(Config { input, templates, rules })
-}
\ No newline at end of file
+    for key in table.keys() {
+        if !["input", "persistence", "sender", "tracking", "purge", "updates", "queues", "templates", "rules"].contains(&key.as_str()) {
+            errors.unknown_key(key);
+        }
+    }
+
+    let config = Config { input, persistence, sender, tracking, purge, queues, templates, rules };
+    check_references(&config, &mut errors);
+
+    if errors.0.is_empty() {
+        Ok(config)
+    } else {
+        Err(Box::new(errors))
+    }
+}
@@ -0,0 +1,308 @@
+use crate::rules::{ArgList, Condition, compile_nation_arg, compile_regex, compile_region_arg, event_name};
+
+/// A token of a `match` expression, with its position in the source.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Ident(String),
+    Str(String),
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {},
+            '(' => tokens.push((position, Token::LParen)),
+            ')' => tokens.push((position, Token::RParen)),
+            // Strings have no escapes, so regex patterns can be written as they are
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, next)) => value.push(next),
+                        None => return Err(format!("unterminated string starting at position {}", position)),
+                    }
+                }
+                tokens.push((position, Token::Str(value)));
+            },
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some((_, next)) = chars.peek() && (next.is_ascii_alphanumeric() || *next == '_') {
+                    word.push(*next);
+                    chars.next();
+                }
+
+                tokens.push((position, match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Ident(word),
+                }));
+            },
+            _ => return Err(format!("unexpected character '{}' at position {}", c, position)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    /// Length of the source, to report errors at the end of the expression.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map(|(position, _)| *position).unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.index += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> Result<(), String> {
+        let position = self.position();
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(format!("expected {} at position {}", description, position)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            conditions.push(self.and()?);
+        }
+
+        Ok(if conditions.len() == 1 { conditions.remove(0) } else { Condition::Or(conditions) })
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            conditions.push(self.unary()?);
+        }
+
+        Ok(if conditions.len() == 1 { conditions.remove(0) } else { Condition::And(conditions) })
+    }
+
+    fn unary(&mut self) -> Result<Condition, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Condition, String> {
+        let position = self.position();
+
+        match self.next() {
+            Some(Token::LParen) => {
+                let condition = self.or()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(condition)
+            },
+            Some(Token::Ident(predicate)) => {
+                self.expect(Token::LParen, &format!("'(' after '{}'", predicate))?;
+
+                let arg_position = self.position();
+                let Some(Token::Str(arg)) = self.next() else {
+                    return Err(format!("expected a quoted argument at position {}", arg_position));
+                };
+
+                self.expect(Token::RParen, "')'")?;
+                compile_predicate(&predicate, &arg).map_err(|err| format!("{} at position {}", err, position))
+            },
+            Some(_) => Err(format!("expected a predicate or '(' at position {}", position)),
+            None => Err(format!("unexpected end of expression at position {}", position)),
+        }
+    }
+}
+
+fn compile_predicate(predicate: &str, arg: &str) -> Result<Condition, String> {
+    if arg.starts_with('!') {
+        return Err(format!("'{}' can't be negated with '!' in an expression, use 'not' instead", arg));
+    }
+
+    match predicate {
+        "nation" => {
+            let mut nations = ArgList::new();
+            nations.push(compile_nation_arg(arg)?);
+            Ok(Condition::Nations(nations))
+        },
+        "region" => {
+            let mut regions = ArgList::new();
+            regions.push(compile_region_arg(arg)?);
+            Ok(Condition::Regions(regions))
+        },
+        "receptor" => {
+            let mut receptors = ArgList::new();
+            receptors.push(compile_nation_arg(arg)?);
+            Ok(Condition::Receptors(receptors))
+        },
+        "other_region" => {
+            let mut regions = ArgList::new();
+            regions.push(compile_region_arg(arg)?);
+            Ok(Condition::OtherRegions(regions))
+        },
+        "text" => Ok(Condition::Text(compile_regex(arg)?)),
+        "event" => match event_name(arg) {
+            Some(event) => Ok(Condition::Event(vec![event])),
+            None => Err(format!("unknown event '{}'", arg)),
+        },
+        _ => Err(format!("unknown predicate '{}'", predicate)),
+    }
+}
+
+/// Compiles a rule's `match` expression, such as
+/// `nation('$is_wa') and not (nation('$numbered_puppet') or region('suspicious'))`.
+///
+/// Predicates are `nation(...)`, `receptor(...)`, `region(...)` and `other_region(...)`, which take
+/// the same arguments as the matching lists, `event(...)`, which takes an event name, and `text(...)`,
+/// which takes a regex matched against the raw event text. They can be combined with
+/// `and`, `or`, `not` and parentheses, with `not` binding tightest and `or` loosest.
+pub fn compile_expression(source: &str) -> Result<Condition, String> {
+    let mut parser = Parser { tokens: tokenize(source)?, index: 0, end: source.len() };
+    let condition = parser.or()?;
+
+    if parser.peek().is_some() {
+        return Err(format!("unexpected token at position {}", parser.position()));
+    }
+
+    Ok(condition)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders the structure of a condition, e.g. `or(nation, and(region, not(event)))`.
+    fn shape(condition: &Condition) -> String {
+        let list = |name: &str, conditions: &[Condition]| format!(
+            "{}({})", name, conditions.iter().map(shape).collect::<Vec<_>>().join(", ")
+        );
+
+        match condition {
+            Condition::And(conditions) => list("and", conditions),
+            Condition::Or(conditions) => list("or", conditions),
+            Condition::Not(condition) => format!("not({})", shape(condition)),
+            Condition::Event(_) => "event".into(),
+            Condition::Regions(_) => "region".into(),
+            Condition::Nations(_) => "nation".into(),
+            Condition::Receptors(_) => "receptor".into(),
+            Condition::OtherRegions(_) => "other_region".into(),
+            Condition::Text(regex) => format!("text({})", regex.as_str()),
+        }
+    }
+
+    fn parse(source: &str) -> String {
+        shape(&compile_expression(source).unwrap_or_else(|err| panic!("{:?} failed to compile: {}", source, err)))
+    }
+
+    fn error(source: &str) -> String {
+        compile_expression(source).expect_err(source)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parse("nation('a') or region('b') and event('cte')"), "or(nation, and(region, event))");
+        assert_eq!(parse("nation('a') and region('b') or event('cte')"), "or(and(nation, region), event)");
+        assert_eq!(parse("nation('a') or region('b') or event('cte')"), "or(nation, region, event)");
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        assert_eq!(parse("not nation('a') and region('b')"), "and(not(nation), region)");
+        assert_eq!(parse("not nation('a') or region('b')"), "or(not(nation), region)");
+        assert_eq!(parse("not not nation('a')"), "not(not(nation))");
+        assert_eq!(parse("not (nation('a') or region('b'))"), "not(or(nation, region))");
+    }
+
+    #[test]
+    fn nested_parentheses() {
+        assert_eq!(
+            parse("((nation('a') or (region('b'))) and (not (receptor('c') and other_region('d'))))"),
+            "and(or(nation, region), not(and(receptor, other_region)))"
+        );
+        assert_eq!(parse("(((event('move_to'))))"), "event");
+    }
+
+    #[test]
+    fn strings_have_no_escapes() {
+        assert_eq!(parse(r"text('\d+\s')"), r"text(\d+\s)");
+        assert_eq!(parse(r#"text("it's")"#), "text(it's)");
+        assert_eq!(parse(r#"text('say "hi"')"#), r#"text(say "hi")"#);
+        assert_eq!(parse("nation('$re:^a|b$') and region(\"$re:(x)\")"), "and(nation, region)");
+    }
+
+    #[test]
+    fn whitespace_is_ignored() {
+        assert_eq!(parse("  nation ( 'a' )and\n\tregion('b')  "), "and(nation, region)");
+    }
+
+    #[test]
+    fn reports_token_errors_with_positions() {
+        assert_eq!(error("nation('a'"), "expected ')' at position 10");
+        assert_eq!(error("nation('a') region('b')"), "unexpected token at position 12");
+        assert_eq!(error("nation('a') and"), "unexpected end of expression at position 15");
+        assert_eq!(error("nation('a') and )"), "expected a predicate or '(' at position 16");
+        assert_eq!(error("(nation('a')"), "expected ')' at position 12");
+        assert_eq!(error("nation 'a'"), "expected '(' after 'nation' at position 7");
+        assert_eq!(error("nation(a)"), "expected a quoted argument at position 7");
+        assert_eq!(error("nation()"), "expected a quoted argument at position 7");
+        assert_eq!(error(""), "unexpected end of expression at position 0");
+    }
+
+    #[test]
+    fn reports_tokenizer_errors_with_positions() {
+        assert_eq!(error("nation('a') and region('b"), "unterminated string starting at position 23");
+        assert_eq!(error("nation('a') && region('b')"), "unexpected character '&' at position 12");
+        assert_eq!(error("nation('a') | region('b')"), "unexpected character '|' at position 12");
+    }
+
+    #[test]
+    fn reports_predicate_errors_with_positions() {
+        assert_eq!(error("nations('a')"), "unknown predicate 'nations' at position 0");
+        assert_eq!(error("nation('a') and event('moved')"), "unknown event 'moved' at position 16");
+        assert_eq!(error("nation('$unknown')"), "unknown command '$unknown' at position 0");
+        assert_eq!(
+            error("region('a') or nation('!b')"),
+            "'!b' can't be negated with '!' in an expression, use 'not' instead at position 15"
+        );
+        assert!(error("text('(')").starts_with("invalid regex pattern '(': "));
+    }
+
+    #[test]
+    fn finds_excluded_nation_predicates() {
+        let excludes_unrecruitable = |source: &str| compile_expression(source).unwrap().excludes_nations(
+            &|predicate| matches!(predicate, crate::rules::NationPredicate::RecruitmentDisabled)
+        );
+
+        assert!(excludes_unrecruitable("not nation('$recruitment_disabled')"));
+        assert!(excludes_unrecruitable("event('found') or (region('x') and not nation('$recruitment_disabled'))"));
+        assert!(!excludes_unrecruitable("nation('$recruitment_disabled')"));
+        assert!(!excludes_unrecruitable("not (event('found') and not nation('$recruitment_disabled'))"));
+        assert!(!excludes_unrecruitable("not nation('$is_wa')"));
+        assert!(!excludes_unrecruitable("not receptor('$recruitment_disabled')"));
+    }
+}
@@ -4,35 +4,53 @@ mod rules;
 mod server;
 mod api;
 mod cache;
+mod persist;
+mod reload;
+mod metrics;
+mod tracking;
+mod expr;
+mod schedule;
+mod revalidate;
 
 use std::{error::Error, sync::Arc, process::exit};
 use rand::{rngs::ThreadRng, seq::IndexedRandom};
-use tokio::sync::Mutex;
-use log::{error, info};
+use tokio::sync::{Mutex, RwLock};
+use log::{error, info, warn};
 
 use caramel::{ns::{UserAgent, api::Client}, akari, log::setup_log, types::akari::Event};
 
 use crate::{cache::{Cache, spawn_wa_worker}, server::start_api_server};
-use crate::tgloop::{Telegram, TelegramState, start_telegram_loop};
-use crate::config::{Config, parse_config};
+use crate::tgloop::{EnqueueError, Telegram, TelegramState, start_telegram_loop};
+use crate::config::{Config, SharedConfig, parse_config};
+use crate::persist::{load_snapshot, spawn_state_writer, unix_now};
+use crate::reload::spawn_config_watcher;
+use crate::metrics::METRICS;
+use crate::tracking::TelegramOrigin;
 
 const PROGRAM: &str = "crystal";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHOR: &str = "Merethin";
-const CONFIG_PATH: &'static str = "config/crystal.toml";
+const CONFIG_PATH: &str = "config/crystal.toml";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     setup_log(vec![]);
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("check-config") {
+        let path = args.get(2).map(String::as_str).unwrap_or(CONFIG_PATH);
+        exit(check_config(path));
+    }
+
     dotenv::dotenv().ok();
 
     let user_agent = UserAgent::read_from_env(PROGRAM, VERSION, AUTHOR);
 
-    let config = parse_config(CONFIG_PATH).unwrap_or_else(|err| {
+    let mut config = parse_config(CONFIG_PATH).unwrap_or_else(|err| {
         error!("Failed to read config file: {}", err);
         exit(1);
     });
+    config.register_metrics();
 
     let url = std::env::var("RABBITMQ_URL").unwrap_or_else(|err| {
         error!("Missing RABBITMQ_URL environment variable: {err}");
@@ -57,24 +75,55 @@ async fn main() -> Result<(), Box<dyn Error>> {
         exit(1);
     }));
 
-    let state = Arc::new(Mutex::new(TelegramState::new()));
+    let mut tg_state = TelegramState::new(&config.queues, config.sender.paused);
+    tg_state.set_history_days(config.sender.history_days);
+    tg_state.set_retry_policy(config.sender.max_retries, config.sender.retry_backoff);
+    tg_state.set_conversion_window(config.tracking.window_hours * 60 * 60);
+    if let Some(snapshot) = load_snapshot(&config.persistence.path) {
+        tg_state.restore(snapshot);
+        tg_state.update_templates(&config.templates);
+    }
+    tg_state.persist_to(spawn_state_writer(config.persistence.path.clone()));
+
+    let state = Arc::new(Mutex::new(tg_state));
     let cache = spawn_wa_worker(client.clone());
 
     cache.wa_signal.send(()).await.unwrap_or_else(|err| {
         error!("Failed to trigger WA nation update: {err}");
     });
 
-    start_telegram_loop(client.clone(), state.clone());
-    start_api_server(state.clone(), auth_key).await?;
+    let config: SharedConfig = Arc::new(RwLock::new(Arc::new(config)));
+
+    start_telegram_loop(client.clone(), state.clone(), cache.clone());
+    start_api_server(state.clone(), cache.clone(), config.clone(), CONFIG_PATH.into(), auth_key).await?;
+    spawn_config_watcher(CONFIG_PATH.into(), config.clone(), state.clone());
 
     let mut rng = rand::rng();
     while let Some(event) = akari::consume(&mut consumer).await {
+        let config = config.read().await.clone();
         process_event(&config, state.clone(), event, cache.clone(), &mut rng).await;
     }
 
     Ok(())
 }
 
+/// Validates the config file at `path` without starting anything, for `crystal check-config <path>`.
+fn check_config(path: &str) -> i32 {
+    match parse_config(path) {
+        Ok(config) => {
+            println!(
+                "Config at '{}' is valid: {} queues, {} templates, {} rules", 
+                path, config.queues.len(), config.templates.len(), config.rules.len()
+            );
+            0
+        },
+        Err(err) => {
+            eprintln!("Config at '{}' is invalid: {}", path, err);
+            1
+        }
+    }
+}
+
 pub async fn process_event(
     config: &Config,
     state: Arc<Mutex<TelegramState>>, 
@@ -82,6 +131,8 @@ pub async fn process_event(
     cache: Arc<Cache>,
     rng: &mut ThreadRng,
 ) {
+    METRICS.events_consumed.inc();
+
     if event.category == "connmiss" {
         cache.wa_signal.send(()).await.unwrap_or_else(|err| {
             error!("Failed to trigger WA nation update: {err}");
@@ -90,25 +141,69 @@ pub async fn process_event(
 
     update_wa(&event, cache.clone()).await;
 
+    // Purge before matching, so anything queued for this event by the rules below is kept
+    purge_nation(config, &event, &state).await;
+
+    let subjects = rules::subjects(&event, &cache).await;
+    let now = unix_now();
+
     for (rule_name, rule) in &config.rules {
-        if rules::match_rule(&event, rule, cache.clone()).await {
-            if let Some(template) = rule.templates.choose(rng).and_then(
-                |key| config.templates.get(key)
-            ) && let Some(nation) = &event.actor {
-                let mut state = state.lock().await;
-                let success = state.add_telegram_to_queue(&rule.queue, Telegram::new(
-                    nation.clone(), template.tgid.clone(), 
-                    template.tg_key.clone(), template.client_key.clone()
-                )).await;
+        if let Some(schedule) = &rule.schedule && !schedule.is_active(now) { continue; }
+
+        if let Some(nation) = rules::match_rule(&subjects, rule) {
+            if let Some((template_name, template)) = rule.templates.choose_weighted(rng, |t| t.weight).ok().and_then(
+                |t| config.templates.get(&t.name).map(|template| (&t.name, template))
+            ) {
+                let telegram = Telegram {
+                    origin: Some(TelegramOrigin {
+                        rule: rule_name.clone(),
+                        template: template_name.clone(),
+                        target_region: rule.target_region.clone(),
+                    }),
+                    revalidate: rule.revalidate.clone(),
+                    ..Telegram::new(
+                        nation.clone(), template.tgid.clone(), 
+                        template.tg_key.clone(), template.client_key.clone()
+                    )
+                };
 
-                if success {
-                    info!("Nation '{}' added to queue '{}', matching rule '{}'", nation, rule.queue, rule_name);
+                let mut state = state.lock().await;
+                let result = state.add_telegram_to_queue(&rule.queue, telegram).await;
+
+                match result {
+                    Ok(()) => info!(
+                        "Nation '{}' added to queue '{}' with template '{}', matching rule '{}'",
+                        nation, rule.queue, template_name, rule_name
+                    ),
+                    Err(EnqueueError::NoSuchQueue) => warn!("Rule '{}' uses queue '{}', which doesn't exist", rule_name, rule.queue),
+                    Err(err) => info!("Nation '{}' not added to queue '{}', matching rule '{}': {}", nation, rule.queue, rule_name, err),
                 }
             }
 
-            break;
+            if !rule.continue_matching { break; }
         }
     }
+
+    if event.category == "move" && let Some(nation) = &event.actor && let Some(region) = &event.destination {
+        state.lock().await.record_move(nation, region);
+    }
+}
+
+/// Removes a nation from every queue if the event means it shouldn't be telegrammed anymore.
+async fn purge_nation(config: &Config, event: &Event, state: &Arc<Mutex<TelegramState>>) {
+    let (nation, reason) = match event.category.as_str() {
+        "ncte" if config.purge.on_cte => (&event.receptor, "cte"),
+        "move" if event.destination.as_ref().is_some_and(|region| config.purge.regions.contains(region)) => (&event.actor, "moved"),
+        _ => return,
+    };
+
+    let Some(nation) = nation else { return; };
+    let removed = state.lock().await.remove_nation(None, nation).unwrap_or(0);
+
+    if removed > 0 {
+        METRICS.purged.inc_by(reason, removed as u64);
+        info!("Purged {} telegrams to nation '{}' from all queues ({})", removed, nation, reason);
+    }
 }
 
 async fn update_wa(event: &Event, cache: Arc<Cache>) {
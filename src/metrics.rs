@@ -0,0 +1,193 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{Arc, LazyLock, Mutex, atomic::{AtomicU64, Ordering}}};
+
+/// A counter with a single label, e.g. `crystal_enqueued_total{queue="regional"}`.
+pub struct LabeledCounter {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    const fn new(name: &'static str, help: &'static str, label: &'static str) -> Self {
+        Self { name, help, label, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, label_value: &str) {
+        self.inc_by(label_value, 1);
+    }
+
+    pub fn inc_by(&self, label_value: &str, amount: u64) {
+        let mut values = self.values.lock().unwrap_or_else(|err| err.into_inner());
+
+        match values.get_mut(label_value) {
+            Some(value) => *value += amount,
+            None => { values.insert(label_value.to_string(), amount); },
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        let values = self.values.lock().unwrap_or_else(|err| err.into_inner());
+
+        write_header(out, self.name, self.help, "counter");
+        for (label_value, value) in values.iter() {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", self.name, self.label, escape_label(label_value), value);
+        }
+    }
+}
+
+/// A counter without labels.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self { name, help, value: AtomicU64::new(0) }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "counter");
+        let _ = writeln!(out, "{} {}", self.name, self.value.load(Ordering::Relaxed));
+    }
+}
+
+/// How many events a single rule was checked against, and how many it matched.
+#[derive(Debug, Default)]
+pub struct RuleCounter {
+    evaluations: AtomicU64,
+    matches: AtomicU64,
+}
+
+impl RuleCounter {
+    pub fn record(&self, matched: bool) {
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        if matched {
+            self.matches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn evaluations(&self) -> u64 {
+        self.evaluations.load(Ordering::Relaxed)
+    }
+
+    pub fn matches(&self) -> u64 {
+        self.matches.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters for every rule, by name. Rules get theirs when the config is loaded, so counting an
+/// evaluation is just an atomic add, and reloading a rule keeps its counts.
+pub struct RuleCounters {
+    rules: Mutex<BTreeMap<String, Arc<RuleCounter>>>,
+}
+
+impl RuleCounters {
+    const fn new() -> Self {
+        Self { rules: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn register(&self, rule: &str) -> Arc<RuleCounter> {
+        let mut rules = self.rules.lock().unwrap_or_else(|err| err.into_inner());
+        rules.entry(rule.to_string()).or_default().clone()
+    }
+
+    /// The counter of every rule registered so far, including rules removed by a reload.
+    pub fn counters(&self) -> BTreeMap<String, Arc<RuleCounter>> {
+        self.rules.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn render(&self, out: &mut String) {
+        let rules = self.rules.lock().unwrap_or_else(|err| err.into_inner());
+
+        write_header(out, "crystal_rule_evaluations_total", "Events evaluated against each rule", "counter");
+        for (rule, counter) in rules.iter() {
+            let _ = writeln!(
+                out, "crystal_rule_evaluations_total{{rule=\"{}\"}} {}", escape_label(rule), counter.evaluations()
+            );
+        }
+
+        write_header(out, "crystal_rule_matches_total", "Events that matched each rule", "counter");
+        for (rule, counter) in rules.iter() {
+            let _ = writeln!(
+                out, "crystal_rule_matches_total{{rule=\"{}\"}} {}", escape_label(rule), counter.matches()
+            );
+        }
+    }
+}
+
+pub struct Metrics {
+    pub events_consumed: Counter,
+    pub rules: RuleCounters,
+    pub enqueued: LabeledCounter,
+    pub telegrams_sent: LabeledCounter,
+    pub send_failures: LabeledCounter,
+    pub can_telegram_requests: Counter,
+    pub purged: LabeledCounter,
+    pub expired: LabeledCounter,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    events_consumed: Counter::new(
+        "crystal_events_consumed_total", "Akari events consumed"
+    ),
+    rules: RuleCounters::new(),
+    enqueued: LabeledCounter::new(
+        "crystal_enqueued_total", "Telegrams added to each queue", "queue"
+    ),
+    telegrams_sent: LabeledCounter::new(
+        "crystal_telegrams_sent_total", "Telegrams successfully sent, by telegram ID", "tgid"
+    ),
+    send_failures: LabeledCounter::new(
+        "crystal_send_failures_total", "Failed sendTG requests, by failure type", "type"
+    ),
+    can_telegram_requests: Counter::new(
+        "crystal_can_telegram_requests_total", "tgcanrecruit API requests made"
+    ),
+    purged: LabeledCounter::new(
+        "crystal_purged_total", "Queued telegrams removed because their nation stopped qualifying, by reason", "reason"
+    ),
+    expired: LabeledCounter::new(
+        "crystal_expired_total", "Queued telegrams dropped for being older than their queue's max age", "queue"
+    ),
+});
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders every metric in the Prometheus text format.
+/// Gauges are read from the current state by the caller and passed in.
+pub fn render(queue_depths: &[(String, usize)], wa_nations: usize) -> String {
+    let mut out = String::new();
+
+    METRICS.events_consumed.render(&mut out);
+    METRICS.rules.render(&mut out);
+    METRICS.enqueued.render(&mut out);
+    METRICS.telegrams_sent.render(&mut out);
+    METRICS.send_failures.render(&mut out);
+    METRICS.can_telegram_requests.render(&mut out);
+    METRICS.purged.render(&mut out);
+    METRICS.expired.render(&mut out);
+
+    write_header(&mut out, "crystal_queue_depth", "Telegrams currently waiting in each queue", "gauge");
+    for (queue, depth) in queue_depths {
+        let _ = writeln!(out, "crystal_queue_depth{{queue=\"{}\"}} {}", escape_label(queue), depth);
+    }
+
+    write_header(&mut out, "crystal_wa_nations", "Nations in the cached WA member list", "gauge");
+    let _ = writeln!(out, "crystal_wa_nations {}", wa_nations);
+
+    out
+}
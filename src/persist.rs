@@ -0,0 +1,143 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::{HashMap, VecDeque}, fs, io, path::Path, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use tokio::sync::watch;
+
+use crate::tgloop::{DeadLetter, Telegram};
+use crate::tracking::Tracker;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub identifier: String,
+    pub telegrams: Vec<Telegram>,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// Unix timestamps (seconds) of the last telegrams sent with a client key.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ClientSnapshot {
+    pub last_recruitment_time: Option<u64>,
+    pub last_telegram_time: Option<u64>,
+}
+
+/// A telegram that was sent, kept to avoid sending the same template to a nation twice.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SentRecord {
+    pub nation: String,
+    pub tgid: String,
+    pub sent_at: u64,
+}
+
+/// Everything needed to pick the telegram loop back up after a restart.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub queues: Vec<QueueSnapshot>,
+    #[serde(default)]
+    pub clients: HashMap<String, ClientSnapshot>,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub history: Vec<SentRecord>,
+    #[serde(default)]
+    pub dead_letters: Vec<DeadLetter>,
+    #[serde(default)]
+    pub tracker: Tracker,
+}
+
+/// A [`Snapshot`] borrowing from the live state, so saving doesn't have to clone everything first.
+/// Serializes to the same format.
+#[derive(Serialize)]
+pub struct SnapshotRef<'a> {
+    pub queues: Vec<QueueSnapshotRef<'a>>,
+    pub clients: HashMap<&'a str, ClientSnapshot>,
+    pub paused: bool,
+    pub history: Vec<SentRecordRef<'a>>,
+    pub dead_letters: &'a [DeadLetter],
+    pub tracker: &'a Tracker,
+}
+
+#[derive(Serialize)]
+pub struct QueueSnapshotRef<'a> {
+    pub identifier: &'a str,
+    pub telegrams: &'a VecDeque<Telegram>,
+    pub paused: bool,
+}
+
+#[derive(Serialize)]
+pub struct SentRecordRef<'a> {
+    pub nation: &'a str,
+    pub tgid: &'a str,
+    pub sent_at: u64,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub fn instant_to_unix(instant: Instant) -> u64 {
+    let age = Instant::now().saturating_duration_since(instant);
+    (SystemTime::now() - age).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Returns None if the timestamp is too far in the past to be represented as an Instant,
+/// in which case it's old enough not to matter for rate limiting anyway.
+pub fn unix_to_instant(timestamp: u64) -> Option<Instant> {
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_secs(timestamp))
+        .unwrap_or_default();
+    Instant::now().checked_sub(age)
+}
+
+pub fn load_snapshot(path: &str) -> Option<Snapshot> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            info!("No saved state found at '{}', starting with empty queues", path);
+            return None;
+        },
+        Err(err) => {
+            warn!("Failed to read saved state from '{}': {}", path, err);
+            return None;
+        }
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(snapshot) => Some(snapshot),
+        Err(err) => {
+            warn!("Saved state at '{}' is invalid, ignoring it: {}", path, err);
+            None
+        }
+    }
+}
+
+fn write_snapshot(path: &str, contents: &str) -> io::Result<()> {
+    if let Some(parent) = Path::new(path).parent() && !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so a crash mid-write never leaves a truncated state file
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Spawns a task that writes every snapshot sent through the returned channel to `path`.
+/// Snapshots sent while a write is in progress are coalesced, only the latest one is written.
+pub fn spawn_state_writer(path: String) -> watch::Sender<String> {
+    let (tx, mut rx) = watch::channel(String::new());
+
+    tokio::spawn(async move {
+        while rx.changed().await.is_ok() {
+            let contents = rx.borrow_and_update().clone();
+            let path = path.clone();
+
+            let result = tokio::task::spawn_blocking(move || write_snapshot(&path, &contents)).await;
+            if let Ok(Err(err)) = result {
+                warn!("Failed to save state: {}", err);
+            }
+        }
+    });
+
+    tx
+}
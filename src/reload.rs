@@ -0,0 +1,86 @@
+use log::{error, info, warn};
+use std::{fs, sync::Arc, time::{Duration, SystemTime}};
+use tokio::{signal::unix::{SignalKind, signal}, sync::Mutex};
+
+use crate::config::{SharedConfig, parse_config};
+use crate::tgloop::TelegramState;
+
+const WATCH_INTERVAL: u64 = 5;
+
+/// Re-parses the config file and swaps it in if it's valid. On failure, the old config stays active.
+pub async fn reload_config(
+    path: &str, config: &SharedConfig, state: &Arc<Mutex<TelegramState>>
+) -> Result<(), String> {
+    let mut new_config = parse_config(path).map_err(|err| err.to_string())?;
+
+    let mut state = state.lock().await;
+    let mut config = config.write().await;
+
+    if new_config.input.exchange_name != config.input.exchange_name {
+        warn!("Changing 'input.exchange_name' requires a restart, keeping '{}' for now", config.input.exchange_name);
+    }
+
+    if new_config.persistence.path != config.persistence.path {
+        warn!("Changing 'persistence.path' requires a restart, keeping '{}' for now", config.persistence.path);
+    }
+
+    // Update queues first so new rules never point at a queue that doesn't exist yet
+    state.update_queues(&new_config.queues);
+    state.update_templates(&new_config.templates);
+    state.set_history_days(new_config.sender.history_days);
+    state.set_retry_policy(new_config.sender.max_retries, new_config.sender.retry_backoff);
+    state.set_conversion_window(new_config.tracking.window_hours * 60 * 60);
+    new_config.register_metrics();
+    *config = Arc::new(new_config);
+
+    info!("Reloaded config from '{}'", path);
+    Ok(())
+}
+
+async fn reload_or_log(path: &str, config: &SharedConfig, state: &Arc<Mutex<TelegramState>>) {
+    reload_config(path, config, state).await.unwrap_or_else(|err| {
+        error!("Failed to reload config, keeping the old one: {}", err);
+    });
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reloads the config whenever the file changes on disk or the process receives SIGHUP.
+pub fn spawn_config_watcher(path: String, config: SharedConfig, state: Arc<Mutex<TelegramState>>) {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(err) => {
+                warn!("Failed to listen for SIGHUP, config will only reload on file changes: {err}");
+                None
+            }
+        };
+
+        let mut last_modified = modified_time(&path);
+        let mut interval = tokio::time::interval(Duration::from_secs(WATCH_INTERVAL));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let modified = modified_time(&path);
+                    if modified == last_modified { continue; }
+
+                    last_modified = modified;
+                    info!("Config file '{}' changed, reloading", path);
+                },
+                Some(_) = async {
+                    match &mut hangup {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    info!("Received SIGHUP, reloading config");
+                },
+            }
+
+            reload_or_log(&path, &config, &state).await;
+        }
+    });
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+
+use crate::api::{NationStatus, query_nation_status};
+use crate::cache::Cache;
+use crate::config::canonicalize_region;
+
+/// A condition checked again right before a queued telegram is sent. Telegrams whose nation no longer
+/// meets every condition are skipped, so rate limited sends aren't spent on nations that stopped qualifying.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Revalidation {
+    /// The nation still exists.
+    Exists,
+    /// The nation is still in the WA, going by the cached WA member list.
+    Wa,
+    /// The nation still accepts recruitment telegrams.
+    Recruitable,
+    /// The nation is in this region.
+    InRegion(String),
+    /// The nation isn't in this region, e.g. because it already joined ours.
+    NotInRegion(String),
+}
+
+impl Revalidation {
+    /// Parses a `revalidate` entry: `exists`, `wa`, `recruitable`, `region:<name>` or `not_region:<name>`.
+    pub fn parse(check: &str) -> Result<Self, String> {
+        if let Some(region) = check.strip_prefix("region:") {
+            return Ok(Self::InRegion(canonicalize_region(region)));
+        }
+
+        if let Some(region) = check.strip_prefix("not_region:") {
+            return Ok(Self::NotInRegion(canonicalize_region(region)));
+        }
+
+        match check {
+            "exists" => Ok(Self::Exists),
+            "wa" => Ok(Self::Wa),
+            "recruitable" => Ok(Self::Recruitable),
+            _ => Err(format!("unknown check '{}', expected 'exists', 'wa', 'recruitable', 'region:<name>' or 'not_region:<name>'", check)),
+        }
+    }
+
+    fn needs_status(&self) -> bool {
+        matches!(self, Self::Exists | Self::InRegion(_) | Self::NotInRegion(_))
+    }
+
+    /// Why a nation failing this check no longer qualifies.
+    fn failure(&self) -> String {
+        match self {
+            Self::Exists => "nation no longer exists".into(),
+            Self::Wa => "nation is no longer in the WA".into(),
+            Self::Recruitable => "nation has recruitment telegrams disabled".into(),
+            Self::InRegion(region) => format!("nation is no longer in region '{}'", region),
+            Self::NotInRegion(region) => format!("nation is now in region '{}'", region),
+        }
+    }
+}
+
+/// Runs every check against a nation, returning why it no longer qualifies if any check fails.
+/// Checks that can't be run because the API is unavailable are skipped, so telegrams aren't lost to API errors.
+pub async fn revalidate(cache: &Cache, nation: &str, checks: &[Revalidation]) -> Result<(), String> {
+    // A single request covers every check that needs the nation's current status
+    let status = match checks.iter().any(Revalidation::needs_status) {
+        true => query_nation_status(&cache.client, nation).await,
+        false => None,
+    };
+
+    // A nation that doesn't exist fails every check, not just `exists`
+    if let Some(NationStatus::Missing) = status {
+        return Err(Revalidation::Exists.failure());
+    }
+
+    for check in checks {
+        let qualifies = match (check, &status) {
+            (Revalidation::Wa, Some(NationStatus::Exists { wa, .. })) => *wa,
+            (Revalidation::Wa, _) => cache.wa_nations.read().await.contains(nation),
+            (Revalidation::Recruitable, _) => cache.can_recruit(nation).await != Some(false),
+            (Revalidation::InRegion(region), Some(NationStatus::Exists { region: current, .. })) => current == region,
+            (Revalidation::NotInRegion(region), Some(NationStatus::Exists { region: current, .. })) => current != region,
+            _ => true,
+        };
+
+        if !qualifies {
+            return Err(check.failure());
+        }
+    }
+
+    Ok(())
+}
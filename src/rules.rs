@@ -1,165 +1,376 @@
-use std::sync::Arc;
-
-use crate::{api::can_telegram, cache::Cache, config::Rule};
+use crate::{cache::Cache, config::Rule};
 
 use caramel::types::akari::Event;
-use log::warn;
+use regex::Regex;
+
+/// Event names that rules can match on, as produced by `translate_event_category`.
+pub const EVENT_NAMES: &[&str] = &[
+    "move_from", "move_to", "found", "refound", "cte",
+    "apply", "admit", "resign", "wa_kick", "endorse", "unendorse",
+    "kick", "ban", "delegate_change", "region_found", "embassy",
+];
+
+const NUMBERED_PUPPET_PATTERN: &str = "^[0-9a-z_-]+[0-9]+$";
+const ROMAN_PUPPET_PATTERN: &str = "^[0-9a-z_-]+_m{0,4}(cm|cd|d?c{0,3})(xc|xl|l?x{0,3})(ix|iv|v?i{0,3})$";
 
-struct Match {
-    matched: bool,
-    excluded: bool
+#[derive(Debug)]
+pub enum NationPredicate {
+    Name(String),
+    Regex(Regex),
+    IsWa,
+    RecruitmentDisabled,
 }
 
-impl Match {
-    fn new() -> Self {
-        Self { matched: false, excluded: false }
-    }
+#[derive(Debug)]
+pub enum RegionPredicate {
+    Name(String),
+    Regex(Regex),
+}
+
+/// A single compiled entry of a `nations` or `regions` list.
+#[derive(Debug)]
+pub enum Arg<P> {
+    /// `*`, matches anything.
+    Any,
+    Include(P),
+    /// Prefixed with `!`, excludes anything it matches.
+    Exclude(P),
+}
+
+/// A compiled `nations` or `regions` list. Matches if any entry includes the value and no entry excludes it.
+#[derive(Debug)]
+pub struct ArgList<P> {
+    any: bool,
+    include: Vec<P>,
+    exclude: Vec<P>,
+}
 
-    fn match_if(&mut self, matched: bool) {
-        self.matched |= matched;
+impl<P> ArgList<P> {
+    pub fn new() -> Self {
+        Self { any: false, include: Vec::new(), exclude: Vec::new() }
     }
 
-    fn exclude_if(&mut self, excluded: bool) {
-        self.excluded |= excluded;
+    pub fn push(&mut self, arg: Arg<P>) {
+        match arg {
+            Arg::Any => self.any = true,
+            Arg::Include(predicate) => self.include.push(predicate),
+            Arg::Exclude(predicate) => self.exclude.push(predicate),
+        }
     }
 
-    fn matches(&self) -> bool {
-        self.matched && !self.excluded
+    /// An empty list never matches anything.
+    pub fn is_empty(&self) -> bool {
+        !self.any && self.include.is_empty() && self.exclude.is_empty()
     }
 }
 
-fn translate_event_category(
-    event: &Event
-) -> Vec<(String, Option<String>, Option<String>)> {
-    match event.category.as_str() {
-        "move" => vec![
-                    ("move_from".into(), event.actor.clone(), event.origin.clone()),
-                    ("move_to".into(), event.actor.clone(), event.destination.clone())
-                  ],
-        "wadmit" => vec![("admit".into(), event.actor.clone(), event.origin.clone())],
-        "wresign" => vec![("resign".into(), event.actor.clone(), event.origin.clone())],
-        "nfound" => vec![("found".into(), event.actor.clone(), event.origin.clone())],
-        "nrefound" => vec![("refound".into(), event.actor.clone(), event.origin.clone())],
-        _ => vec![]
-    }
+/// A compiled rule condition. The `event`, `regions` and `nations` lists and `match` expressions
+/// all compile to this. Conditions are evaluated left to right and stop as soon as the result is known.
+#[derive(Debug)]
+pub enum Condition {
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+    Event(Vec<&'static str>),
+    Regions(ArgList<RegionPredicate>),
+    Nations(ArgList<NationPredicate>),
+    /// Matches the event's receptor, regardless of which nation the rule is about.
+    Receptors(ArgList<NationPredicate>),
+    OtherRegions(ArgList<RegionPredicate>),
+    /// Matches the raw event text.
+    Text(Regex),
 }
 
-async fn matches_nation_impl(arg: &str, nation: &String, cache: Arc<Cache>) -> bool {
-    if let Some(command) = arg.strip_prefix("$") {
-        let mut regex_cache = cache.regex.write().await;
-        if let Some(pattern) = command.strip_prefix("re:") {
-            if let Ok(regex) = regex_cache.get_regex(pattern) {
-                return regex.is_match(&nation);
-            } else { 
-                warn!("Invalid regex pattern in rule: '$re:{}'", pattern);
-                return false; 
-            }
-        } else if command == "numbered_puppet" {
-            let pattern = "^[0-9a-z_-]+[0-9]+$";
-            if let Ok(regex) = regex_cache.get_regex(pattern) {
-                return regex.is_match(&nation);
-            } else { 
-                return false;
-            }
-        } else if command == "roman_puppet" {
-            let pattern = "^[0-9a-z_-]+_m{0,4}(cm|cd|d?c{0,3})(xc|xl|l?x{0,3})(ix|iv|v?i{0,3})$";
-            if let Ok(regex) = regex_cache.get_regex(pattern) {
-                return regex.is_match(&nation);
-            } else { 
-                return false; 
-            }
-        } else if command == "is_wa" {
-            return cache.wa_nations.read().await.contains(nation);
-        } else if command == "recruitment_disabled" {
-            return !can_telegram(&cache.client, &nation).await;
-        } else {
-            warn!("Invalid command in rule: '${}'", command);
-            return false;
+impl Condition {
+    /// Whether the condition rules out nations that satisfy `f` anywhere: through a `!` entry of a
+    /// `nations` list, or an entry under an odd number of `not`s in a `match` expression.
+    pub fn excludes_nations(&self, f: &impl Fn(&NationPredicate) -> bool) -> bool {
+        self.excludes_nations_under(false, f)
+    }
+
+    /// Same as `excludes_nations`, for a condition that is itself negated if `negated` is set.
+    fn excludes_nations_under(&self, negated: bool, f: &impl Fn(&NationPredicate) -> bool) -> bool {
+        match self {
+            Self::And(conditions) | Self::Or(conditions) => {
+                conditions.iter().any(|condition| condition.excludes_nations_under(negated, f))
+            },
+            Self::Not(condition) => condition.excludes_nations_under(!negated, f),
+            Self::Nations(nations) if negated => nations.include.iter().any(f),
+            Self::Nations(nations) => nations.exclude.iter().any(f),
+            _ => false,
         }
-    } else {
-        return nation == arg;
     }
 }
 
-async fn matches_nation(arg: &str, nation: &Option<String>, cache: Arc<Cache>, match_obj: &mut Match) {
-    if let Some(nation) = nation {
-        if arg == "*" {
-            match_obj.match_if(true);
-        } else if let Some(negated_arg) = arg.strip_prefix("!") {
-            match_obj.exclude_if(matches_nation_impl(negated_arg, nation, cache).await)
-        } else {
-            match_obj.match_if(matches_nation_impl(arg, nation, cache).await);
-        }
+pub fn compile_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|err| format!("invalid regex pattern '{}': {}", pattern, err))
+}
+
+fn compile_arg<P>(arg: &str, compile_command: impl Fn(&str) -> Result<P, String>, name: impl Fn(String) -> P) -> Result<Arg<P>, String> {
+    if arg == "*" {
+        return Ok(Arg::Any);
     }
+
+    let (negated, arg) = match arg.strip_prefix("!") {
+        Some(arg) => (true, arg),
+        None => (false, arg),
+    };
+
+    let predicate = match arg.strip_prefix("$") {
+        Some(command) => compile_command(command)?,
+        None => name(arg.to_string()),
+    };
+
+    Ok(if negated { Arg::Exclude(predicate) } else { Arg::Include(predicate) })
 }
 
-async fn matches_region_impl(arg: &str, region: &String, cache: Arc<Cache>) -> bool {
-    if let Some(command) = arg.strip_prefix("$") {
+/// Compiles a `nations` argument, such as `!$numbered_puppet` or `$re:^nation_[0-9]+$`.
+pub fn compile_nation_arg(arg: &str) -> Result<Arg<NationPredicate>, String> {
+    compile_arg(arg, |command| {
         if let Some(pattern) = command.strip_prefix("re:") {
-            let mut regex_cache = cache.regex.write().await;
-            if let Ok(regex) = regex_cache.get_regex(pattern) {
-                return regex.is_match(&region);
-            } else { 
-                warn!("Invalid regex pattern in rule: '$re:{}'", pattern);
-                return false; 
-            }
+            Ok(NationPredicate::Regex(compile_regex(pattern)?))
         } else {
-            warn!("Invalid command in rule: '${}'", command);
-            return false;
+            match command {
+                "numbered_puppet" => Ok(NationPredicate::Regex(compile_regex(NUMBERED_PUPPET_PATTERN)?)),
+                "roman_puppet" => Ok(NationPredicate::Regex(compile_regex(ROMAN_PUPPET_PATTERN)?)),
+                "is_wa" => Ok(NationPredicate::IsWa),
+                "recruitment_disabled" => Ok(NationPredicate::RecruitmentDisabled),
+                _ => Err(format!("unknown command '${}'", command)),
+            }
         }
-    } else {
-        return region == arg;
-    }
+    }, NationPredicate::Name)
 }
 
-async fn matches_region(arg: &str, region: &Option<String>, cache: Arc<Cache>, match_obj: &mut Match) {
-    if let Some(region) = region {
-        if arg == "*" {
-            match_obj.match_if(true);
-        } else if let Some(negated_arg) = arg.strip_prefix("!") {
-            match_obj.exclude_if(matches_region_impl(negated_arg, region, cache).await)
+/// Compiles a `regions` argument, such as `!testregionia` or `$re:^lazarus`.
+pub fn compile_region_arg(arg: &str) -> Result<Arg<RegionPredicate>, String> {
+    compile_arg(arg, |command| {
+        if let Some(pattern) = command.strip_prefix("re:") {
+            Ok(RegionPredicate::Regex(compile_regex(pattern)?))
         } else {
-            match_obj.match_if(matches_region_impl(arg, region, cache).await);
+            Err(format!("unknown command '${}'", command))
         }
+    }, RegionPredicate::Name)
+}
+
+/// Looks up the static name of an event, so rules don't need to own their event names.
+pub fn event_name(name: &str) -> Option<&'static str> {
+    EVENT_NAMES.iter().find(|event| **event == name).copied()
+}
+
+/// A nation involved in an event, along with what the cache knew about it when the event came in.
+#[derive(Clone, Copy)]
+struct Nation<'a> {
+    name: &'a str,
+    wa: bool,
+    recruitment_disabled: bool,
+}
+
+impl<'a> Nation<'a> {
+    async fn lookup(name: &'a Option<String>, cache: &Cache) -> Option<Self> {
+        let name = name.as_deref()?;
+
+        Some(Self {
+            name,
+            wa: cache.wa_nations.read().await.contains(name),
+            // Only the cache is consulted here, so matching never waits on the API. Nations whose status
+            // isn't cached are checked again just before sending instead.
+            recruitment_disabled: cache.cached_can_recruit(name).await == Some(false),
+        })
     }
 }
 
-async fn match_rule_by_category(
-    rule: &Rule, 
-    cache: Arc<Cache>,
-    category: String, nation: Option<String>, region: Option<String>
-) -> bool {
-    if !rule.event.contains(&category) { return false; }
+/// One way of looking at an Akari event, as a rule sees it.
+struct Subject<'a> {
+    category: &'static str,
+    /// The nation the event is about, which is the one telegrammed if the rule matches.
+    nation: Option<Nation<'a>>,
+    receptor: Option<Nation<'a>>,
+    region: Option<&'a str>,
+    /// The other region involved, e.g. where a nation came from for `move_to`.
+    other_region: Option<&'a str>,
+    event: &'a Event,
+}
 
-    {
-        let mut match_obj = Match::new();
+/// An Akari event translated for matching, with every cache lookup rules need already done.
+pub struct Subjects<'a>([Option<Subject<'a>>; 2]);
 
-        for arg in &rule.nations {
-            matches_nation(arg, &nation, cache.clone(), &mut match_obj).await;
-        }
+/// Translates an event and looks up its nations in the cache, once for all the rules it's checked against.
+pub async fn subjects<'a>(event: &'a Event, cache: &Cache) -> Subjects<'a> {
+    let actor = Nation::lookup(&event.actor, cache).await;
+    let receptor = Nation::lookup(&event.receptor, cache).await;
 
-        if !match_obj.matches() { return false; }
+    Subjects(translate_event_category(event, actor, receptor))
+}
+
+/// Translates an Akari event into the events rules see. In Akari events, the actor is the nation
+/// doing something and the receptor the nation it's done to, so the rule's nation is whichever one
+/// the event is about. Rules can also look at the receptor, the other region of a move or embassy,
+/// and the raw event text.
+///
+/// | Akari category | Rule event        | Nation                             | Region                     |
+/// |----------------|-------------------|------------------------------------|----------------------------|
+/// | `move`         | `move_from`       | moving nation                      | region it left             |
+/// | `move`         | `move_to`         | moving nation                      | region it moved to         |
+/// | `nfound`       | `found`           | founded nation                     | region it was founded in   |
+/// | `nrefound`     | `refound`         | refounded nation                   | region it was refounded in |
+/// | `ncte`         | `cte`             | nation that ceased to exist        | region it was in           |
+/// | `wapply`       | `apply`           | applying nation                    | region it's in             |
+/// | `wadmit`       | `admit`           | admitted nation                    | region it's in             |
+/// | `wresign`      | `resign`          | resigning nation                   | region it's in             |
+/// | `wkick`        | `wa_kick`         | nation kicked from the WA          | region it's in             |
+/// | `wendo`        | `endorse`         | endorsing nation                   | region it's in             |
+/// | `wunendo`      | `unendorse`       | nation withdrawing its endorsement | region it's in             |
+/// | `rkick`        | `kick`            | ejected nation                     | region it was ejected from |
+/// | `rban`         | `ban`             | banned nation                      | region it was banned from  |
+/// | `rdel`         | `delegate_change` | new delegate                       | region                     |
+/// | `rfound`       | `region_found`    | founding nation                    | founded region             |
+/// | `rembassy`     | `embassy`         | nation that proposed it            | either region              |
+fn translate_event_category<'a>(event: &'a Event, actor: Option<Nation<'a>>, receptor: Option<Nation<'a>>) -> [Option<Subject<'a>>; 2] {
+    let subject = |category, nation, region: &'a Option<String>, other_region: &'a Option<String>| Some(Subject {
+        category, nation, receptor, region: region.as_deref(), other_region: other_region.as_deref(), event,
+    });
+
+    match event.category.as_str() {
+        "move" => [
+                    subject("move_from", actor, &event.origin, &event.destination),
+                    subject("move_to", actor, &event.destination, &event.origin),
+                  ],
+        "nfound" => [subject("found", actor, &event.origin, &None), None],
+        "nrefound" => [subject("refound", actor, &event.origin, &None), None],
+        "ncte" => [subject("cte", receptor, &event.origin, &None), None],
+        "wapply" => [subject("apply", actor, &event.origin, &None), None],
+        "wadmit" => [subject("admit", actor, &event.origin, &None), None],
+        "wresign" => [subject("resign", actor, &event.origin, &None), None],
+        "wkick" => [subject("wa_kick", receptor, &event.origin, &None), None],
+        "wendo" => [subject("endorse", actor, &event.origin, &None), None],
+        "wunendo" => [subject("unendorse", actor, &event.origin, &None), None],
+        "rkick" => [subject("kick", receptor, &event.origin, &None), None],
+        "rban" => [subject("ban", receptor, &event.origin, &None), None],
+        "rdel" => [subject("delegate_change", actor, &event.origin, &None), None],
+        "rfound" => [subject("region_found", actor, &event.origin, &None), None],
+        "rembassy" => [
+                    subject("embassy", actor, &event.origin, &event.destination),
+                    subject("embassy", actor, &event.destination, &event.origin),
+                  ],
+        _ => [None, None]
     }
+}
 
-    {
-        let mut match_obj = Match::new();
+impl NationPredicate {
+    fn matches(&self, nation: &Nation) -> bool {
+        match self {
+            Self::Name(name) => nation.name == name,
+            Self::Regex(regex) => regex.is_match(nation.name),
+            Self::IsWa => nation.wa,
+            Self::RecruitmentDisabled => nation.recruitment_disabled,
+        }
+    }
+}
 
-        for arg in &rule.regions {
-            matches_region(arg, &region, cache.clone(), &mut match_obj).await;
+impl RegionPredicate {
+    fn matches(&self, region: &str) -> bool {
+        match self {
+            Self::Name(name) => region == name,
+            Self::Regex(regex) => regex.is_match(region),
         }
+    }
+}
+
+impl<P> ArgList<P> {
+    fn matches_with(&self, matches: impl Fn(&P) -> bool) -> bool {
+        (self.any || self.include.iter().any(&matches)) && !self.exclude.iter().any(&matches)
+    }
+}
 
-        if !match_obj.matches() { return false; }
+impl ArgList<NationPredicate> {
+    fn matches(&self, nation: Option<&Nation>) -> bool {
+        nation.is_some_and(|nation| self.matches_with(|predicate| predicate.matches(nation)))
     }
+}
 
-    return true;
+impl ArgList<RegionPredicate> {
+    fn matches(&self, region: Option<&str>) -> bool {
+        region.is_some_and(|region| self.matches_with(|predicate| predicate.matches(region)))
+    }
 }
 
-pub async fn match_rule(event: &Event, rule: &Rule, cache: Arc<Cache>) -> bool {
-    for (category, nation, region) in translate_event_category(event) {
-        if match_rule_by_category(rule, cache.clone(), category, nation, region).await {
-            return true;
+impl Condition {
+    fn matches(&self, subject: &Subject) -> bool {
+        match self {
+            Self::And(conditions) => conditions.iter().all(|condition| condition.matches(subject)),
+            Self::Or(conditions) => conditions.iter().any(|condition| condition.matches(subject)),
+            Self::Not(condition) => !condition.matches(subject),
+            Self::Event(events) => events.contains(&subject.category),
+            Self::Regions(regions) => regions.matches(subject.region),
+            Self::Nations(nations) => nations.matches(subject.nation.as_ref()),
+            Self::Receptors(receptors) => receptors.matches(subject.receptor.as_ref()),
+            Self::OtherRegions(regions) => regions.matches(subject.other_region),
+            Self::Text(regex) => regex.is_match(&subject.event.event),
         }
     }
+}
+
+/// Checks an event against a rule, returning the nation to telegram if it matches.
+pub fn match_rule(subjects: &Subjects, rule: &Rule) -> Option<String> {
+    let nation = subjects.0.iter().flatten().find_map(
+        |subject| subject.nation.filter(|_| rule.condition.matches(subject)).map(|nation| nation.name.to_string())
+    );
+
+    rule.counter.record(nation.is_some());
+    nation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(category: &str) -> Event {
+        serde_json::from_value(serde_json::json!({
+            "category": category,
+            "actor": "actor",
+            "receptor": "receptor",
+            "origin": "origin",
+            "destination": "destination",
+            "event": "",
+            "time": 0,
+        })).unwrap()
+    }
+
+    fn nation(name: &str) -> Option<Nation<'_>> {
+        Some(Nation { name, wa: false, recruitment_disabled: false })
+    }
+
+    #[test]
+    fn translates_event_categories() {
+        let cases = [
+            ("move", ["move_from", "move_to"], "actor"),
+            ("nfound", ["found", ""], "actor"),
+            ("nrefound", ["refound", ""], "actor"),
+            ("ncte", ["cte", ""], "receptor"),
+            ("wapply", ["apply", ""], "actor"),
+            ("wadmit", ["admit", ""], "actor"),
+            ("wresign", ["resign", ""], "actor"),
+            ("wkick", ["wa_kick", ""], "receptor"),
+            ("wendo", ["endorse", ""], "actor"),
+            ("wunendo", ["unendorse", ""], "actor"),
+            ("rkick", ["kick", ""], "receptor"),
+            ("rban", ["ban", ""], "receptor"),
+            ("rdel", ["delegate_change", ""], "actor"),
+            ("rfound", ["region_found", ""], "actor"),
+            ("rembassy", ["embassy", "embassy"], "actor"),
+            ("unknown", ["", ""], ""),
+        ];
+
+        for (category, expected, side) in cases {
+            let event = event(category);
+            let subjects = translate_event_category(&event, nation("actor"), nation("receptor"));
 
-    false
-}
\ No newline at end of file
+            for (subject, expected) in subjects.iter().zip(expected) {
+                assert_eq!(subject.as_ref().map_or("", |subject| subject.category), expected, "category {}", category);
+                if let Some(subject) = subject {
+                    assert_eq!(subject.nation.as_ref().map(|nation| nation.name), Some(side), "category {}", category);
+                }
+            }
+        }
+    }
+}
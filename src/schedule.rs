@@ -0,0 +1,186 @@
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+pub const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily window, in minutes after midnight UTC. Wraps around midnight if it runs past the end of the day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailyWindow {
+    pub start: u32,
+    pub length: u32,
+}
+
+impl DailyWindow {
+    fn contains(&self, minute: u32) -> bool {
+        (minute + MINUTES_PER_DAY - self.start) % MINUTES_PER_DAY < self.length
+    }
+
+    /// Grows the window by `margin` minutes on both sides.
+    pub fn widen(&self, margin: u32) -> Self {
+        Self {
+            start: (self.start + MINUTES_PER_DAY - margin % MINUTES_PER_DAY) % MINUTES_PER_DAY,
+            length: self.length.saturating_add(margin.saturating_mul(2)).min(MINUTES_PER_DAY),
+        }
+    }
+}
+
+/// When a rule or queue is active. Everything is in UTC, and unset fields don't restrict anything.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    /// Time of day the schedule is active.
+    pub hours: Option<DailyWindow>,
+    /// Days of the week the schedule is active, as a bitmask with Monday as the lowest bit.
+    pub days: Option<u8>,
+    /// Unix timestamp (seconds) the schedule starts being active at.
+    pub start: Option<u64>,
+    /// Unix timestamp (seconds) the schedule stops being active at.
+    pub end: Option<u64>,
+    /// Times of day the schedule is inactive, i.e. updates with a margin around them.
+    pub avoid: Vec<DailyWindow>,
+}
+
+impl Schedule {
+    pub fn is_active(&self, now: u64) -> bool {
+        let minute = ((now % SECONDS_PER_DAY) / 60) as u32;
+        // 1970-01-01 was a Thursday
+        let weekday = ((now / SECONDS_PER_DAY + 3) % 7) as u8;
+
+        self.start.is_none_or(|start| now >= start)
+            && self.end.is_none_or(|end| now < end)
+            && self.days.is_none_or(|days| days & (1 << weekday) != 0)
+            && self.hours.is_none_or(|hours| hours.contains(minute))
+            && !self.avoid.iter().any(|window| window.contains(minute))
+    }
+}
+
+/// Parses a time of day like `16:30` into minutes after midnight.
+pub fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Parses a range of times like `08:00-20:00`. Ranges ending before they start wrap around midnight.
+pub fn parse_hours(range: &str) -> Option<DailyWindow> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (parse_time_of_day(start)?, parse_time_of_day(end)?);
+
+    let length = (end + MINUTES_PER_DAY - start) % MINUTES_PER_DAY;
+    Some(DailyWindow { start, length: if length == 0 { MINUTES_PER_DAY } else { length } })
+}
+
+/// Parses a day of the week like `mon` or `monday`, returning its bit in [`Schedule::days`].
+pub fn parse_weekday(day: &str) -> Option<u8> {
+    const DAYS: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+    let day = day.to_lowercase();
+    let index = DAYS.iter().position(|name| day == *name || day == name[..3])?;
+
+    Some(1 << index)
+}
+
+/// Converts a UTC date and time to a Unix timestamp (seconds).
+pub fn unix_from_utc(year: i64, month: u32, day: u32, seconds_of_day: u64) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) { return None; }
+
+    // Days since the epoch, from https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days).ok().map(|days| days * SECONDS_PER_DAY + seconds_of_day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn converts_the_epoch() {
+        assert_eq!(unix_from_utc(1970, 1, 1, 0), Some(0));
+        assert_eq!(unix_from_utc(1970, 1, 2, 30), Some(SECONDS_PER_DAY + 30));
+        assert_eq!(unix_from_utc(1969, 12, 31, 0), None);
+    }
+
+    #[test]
+    fn converts_leap_days() {
+        assert_eq!(unix_from_utc(2024, 2, 29, 0), Some(1709164800));
+        assert_eq!(unix_from_utc(2024, 3, 1, 0), Some(1709164800 + SECONDS_PER_DAY));
+        // 2000 is a leap year despite being divisible by 100, since it's divisible by 400
+        assert_eq!(unix_from_utc(2000, 2, 29, 0), Some(951782400));
+        assert_eq!(unix_from_utc(2000, 3, 1, 0), Some(951868800));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(unix_from_utc(2024, 0, 1, 0), None);
+        assert_eq!(unix_from_utc(2024, 13, 1, 0), None);
+        assert_eq!(unix_from_utc(2024, 1, 0, 0), None);
+        assert_eq!(unix_from_utc(2024, 1, 32, 0), None);
+    }
+
+    #[test]
+    fn hours_wrap_past_midnight() {
+        let window = parse_hours("22:00-02:00").unwrap();
+        assert_eq!(window, DailyWindow { start: 22 * 60, length: 4 * 60 });
+
+        let schedule = Schedule { hours: Some(window), ..Default::default() };
+        let day = unix_from_utc(2026, 10, 18, 0).unwrap();
+
+        assert!(!schedule.is_active(day + 22 * HOUR - 1));
+        assert!(schedule.is_active(day + 22 * HOUR));
+        assert!(schedule.is_active(day + 24 * HOUR - 1));
+        assert!(schedule.is_active(day + 24 * HOUR));
+        assert!(schedule.is_active(day + 26 * HOUR - 1));
+        assert!(!schedule.is_active(day + 26 * HOUR));
+
+        // Equal start and end mean the whole day
+        assert_eq!(parse_hours("08:00-08:00"), Some(DailyWindow { start: 8 * 60, length: MINUTES_PER_DAY }));
+    }
+
+    #[test]
+    fn widened_windows_wrap_and_saturate() {
+        let window = DailyWindow { start: 10, length: 60 };
+        assert_eq!(window.widen(30), DailyWindow { start: MINUTES_PER_DAY - 20, length: 120 });
+        assert!(window.widen(30).contains(MINUTES_PER_DAY - 20));
+        assert!(window.widen(30).contains(99));
+        assert!(!window.widen(30).contains(100));
+
+        assert_eq!(window.widen(u32::MAX).length, MINUTES_PER_DAY);
+    }
+
+    #[test]
+    fn days_change_at_midnight_utc() {
+        // 1970-01-01 was a Thursday
+        let thursday = Schedule { days: parse_weekday("thursday"), ..Default::default() };
+        assert!(thursday.is_active(0));
+        assert!(thursday.is_active(SECONDS_PER_DAY - 1));
+        assert!(!thursday.is_active(SECONDS_PER_DAY));
+
+        // 2026-10-18 is a Sunday, and the next day a Monday
+        let sunday = unix_from_utc(2026, 10, 18, 0).unwrap();
+        let monday = Schedule { days: parse_weekday("mon"), ..Default::default() };
+        assert!(!monday.is_active(sunday + SECONDS_PER_DAY - 1));
+        assert!(monday.is_active(sunday + SECONDS_PER_DAY));
+        assert!(monday.is_active(sunday + 2 * SECONDS_PER_DAY - 1));
+        assert!(!monday.is_active(sunday + 2 * SECONDS_PER_DAY));
+
+        let weekend = Schedule { days: Some(parse_weekday("sat").unwrap() | parse_weekday("Sunday").unwrap()), ..Default::default() };
+        assert!(weekend.is_active(sunday));
+        assert!(weekend.is_active(sunday - SECONDS_PER_DAY));
+        assert!(!weekend.is_active(sunday - SECONDS_PER_DAY - 1));
+    }
+
+    #[test]
+    fn parses_weekdays() {
+        assert_eq!(parse_weekday("mon"), Some(1));
+        assert_eq!(parse_weekday("Sunday"), Some(1 << 6));
+        assert_eq!(parse_weekday("mond"), None);
+        assert_eq!(parse_weekday("moonday"), None);
+    }
+}
@@ -1,14 +1,19 @@
-use std::{sync::Arc, error::Error};
+use std::{collections::BTreeMap, sync::Arc, error::Error};
 
 use axum::{
-    Json, Router, extract::State, http::{HeaderMap, StatusCode}, response::IntoResponse, routing::post
+    Json, Router, extract::{Path, Query, State}, http::{HeaderMap, StatusCode}, response::IntoResponse, routing::{delete, get, post}
 };
 
 use log::{warn, info};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
+use crate::cache::Cache;
+use crate::config::SharedConfig;
+use crate::metrics::{self, METRICS};
+use crate::reload::reload_config;
 use crate::tgloop::{Telegram, TelegramState};
+use crate::tracking::RuleStats;
 
 #[derive(Debug, Deserialize)]
 pub struct RequestQueryModel {
@@ -17,47 +22,369 @@ pub struct RequestQueryModel {
     tg_key: String,
     client_key: String,
     nations: Vec<String>,
+    /// Send the nations in the order given, even from a LIFO queue, which would otherwise send them last to first.
+    #[serde(default)]
+    preserve_order: bool,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct InspectQueryModel {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemovedResponseModel {
+    removed: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequeueQueryModel {
+    /// Only requeue dead letters to these nations. Requeues everything if missing.
+    nations: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RequeuedResponseModel {
+    requeued: usize,
+    /// Letters left in the list because their telegram is already queued or was recently sent, or their queue is full.
+    skipped: usize,
+}
+
+/// A rule's statistics, with evaluations and matches taken from its metrics counter.
+/// Unlike the rest, those restart from zero when the process restarts.
+#[derive(Debug, Default, Serialize)]
+pub struct RuleStatsModel {
+    /// Events checked against this rule.
+    evaluated: u64,
+    /// Events that matched this rule.
+    matched: u64,
+    #[serde(flatten)]
+    stats: RuleStats,
+}
+
+const DEFAULT_QUEUES_LIMIT: usize = 10;
+const DEFAULT_QUEUE_LIMIT: usize = 100;
+
 #[derive(Clone)]
 struct ServerState {
     tg_state: Arc<Mutex<TelegramState>>,
+    cache: Arc<Cache>,
+    config: SharedConfig,
+    config_path: String,
     auth_key: String,
 }
 
+/// Converts a nation name to the form used in Akari events, e.g. "Testlandia Prime" to "testlandia_prime".
+fn canonicalize_nation(nation: &str) -> String {
+    nation.trim().to_lowercase().replace(' ', "_")
+}
+
+fn is_authorized(state: &ServerState, headers: &HeaderMap) -> bool {
+    let auth_header = headers.get("x-crystal-key").and_then(|header| header.to_str().ok());
+    auth_header == Some(&state.auth_key)
+}
+
 async fn add_telegram(
     State(state): State<ServerState>,
     headers: HeaderMap,
     Json(params): Json<RequestQueryModel>,
 ) -> impl IntoResponse {
-    let auth_header = headers.get("x-crystal-key").and_then(|header| header.to_str().ok());
-
-    if auth_header != Some(&state.auth_key) {
+    if !is_authorized(&state, &headers) {
         return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
     }
 
     let mut state = state.tg_state.lock().await;
 
-    let success = state.add_telegrams_to_queue(&params.queue, 
+    let result = state.add_telegrams_to_queue(&params.queue, 
         params.nations.iter().map(|nation| {
-            Telegram::new(nation.clone(), params.tgid.clone(), params.tg_key.clone(), params.client_key.clone())
-        }).collect()
+            Telegram::new(canonicalize_nation(nation), params.tgid.clone(), params.tg_key.clone(), params.client_key.clone())
+        }).collect(),
+        params.preserve_order,
     ).await;
 
-    if success {
-        info!("{} nations added to queue '{}', using TGID {}, at external request", params.nations.len(), params.queue, params.tgid);
+    match result {
+        Ok(count) => {
+            info!(
+                "{} nations added to queue '{}' ({} skipped as duplicates or because the queue is full), using TGID {}, at external request", 
+                count, params.queue, params.nations.len() - count, params.tgid
+            );
+            (StatusCode::OK, "Success").into_response()
+        },
+        Err(_) => (StatusCode::NOT_FOUND, "No such queue").into_response(),
+    }
+}
+
+async fn list_queues(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Query(params): Query<InspectQueryModel>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let state = state.tg_state.lock().await;
+    Json(state.describe_queues(params.limit.unwrap_or(DEFAULT_QUEUES_LIMIT))).into_response()
+}
+
+async fn get_queue(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(params): Query<InspectQueryModel>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let state = state.tg_state.lock().await;
+    match state.describe_queue(&name, params.limit.unwrap_or(DEFAULT_QUEUE_LIMIT)) {
+        Some(info) => Json(info).into_response(),
+        None => (StatusCode::NOT_FOUND, "No such queue").into_response(),
+    }
+}
+
+async fn remove_nation_from_queue(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path((name, nation)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let nation = canonicalize_nation(&nation);
+    let mut state = state.tg_state.lock().await;
+
+    match state.remove_nation(Some(&name), &nation) {
+        Some(removed) => {
+            info!("Removed {} telegrams to nation '{}' from queue '{}', at external request", removed, nation, name);
+            Json(RemovedResponseModel { removed }).into_response()
+        },
+        None => (StatusCode::NOT_FOUND, "No such queue").into_response(),
+    }
+}
+
+async fn remove_nation_from_all_queues(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(nation): Path<String>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let nation = canonicalize_nation(&nation);
+    let removed = state.tg_state.lock().await.remove_nation(None, &nation).unwrap_or_default();
+    info!("Removed {} telegrams to nation '{}' from all queues, at external request", removed, nation);
+
+    Json(RemovedResponseModel { removed }).into_response()
+}
+
+async fn clear_queue(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    match state.tg_state.lock().await.clear_queue(&name) {
+        Some(removed) => {
+            info!("Cleared {} telegrams from queue '{}', at external request", removed, name);
+            Json(RemovedResponseModel { removed }).into_response()
+        },
+        None => (StatusCode::NOT_FOUND, "No such queue").into_response(),
+    }
+}
+
+async fn move_to_front(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path((name, nation)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let nation = canonicalize_nation(&nation);
+
+    match state.tg_state.lock().await.move_to_front(&name, &nation) {
+        Some(true) => {
+            info!("Moved nation '{}' to the front of queue '{}', at external request", nation, name);
+            (StatusCode::OK, "Success").into_response()
+        },
+        Some(false) => (StatusCode::NOT_FOUND, "Nation is not in this queue").into_response(),
+        None => (StatusCode::NOT_FOUND, "No such queue").into_response(),
     }
+}
+
+async fn set_paused(state: ServerState, headers: HeaderMap, paused: bool) -> axum::response::Response {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    state.tg_state.lock().await.set_paused(paused);
+    info!("Sending {}, at external request", if paused { "paused" } else { "resumed" });
 
     (StatusCode::OK, "Success").into_response()
 }
 
+async fn pause(State(state): State<ServerState>, headers: HeaderMap) -> impl IntoResponse {
+    set_paused(state, headers, true).await
+}
+
+async fn resume(State(state): State<ServerState>, headers: HeaderMap) -> impl IntoResponse {
+    set_paused(state, headers, false).await
+}
+
+async fn set_queue_paused(state: ServerState, headers: HeaderMap, name: String, paused: bool) -> axum::response::Response {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    match state.tg_state.lock().await.set_queue_paused(&name, paused) {
+        Some(()) => {
+            info!("Sending from queue '{}' {}, at external request", name, if paused { "paused" } else { "resumed" });
+            (StatusCode::OK, "Success").into_response()
+        },
+        None => (StatusCode::NOT_FOUND, "No such queue").into_response(),
+    }
+}
+
+async fn pause_queue(State(state): State<ServerState>, headers: HeaderMap, Path(name): Path<String>) -> impl IntoResponse {
+    set_queue_paused(state, headers, name, true).await
+}
+
+async fn resume_queue(State(state): State<ServerState>, headers: HeaderMap, Path(name): Path<String>) -> impl IntoResponse {
+    set_queue_paused(state, headers, name, false).await
+}
+
+async fn list_dead_letters(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    Json(state.tg_state.lock().await.describe_dead_letters()).into_response()
+}
+
+async fn requeue_dead_letters(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(params): Json<RequeueQueryModel>,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let nations = params.nations.map(|nations| nations.iter().map(|nation| canonicalize_nation(nation)).collect::<Vec<_>>());
+    let (requeued, skipped) = state.tg_state.lock().await.requeue_dead_letters(nations.as_deref());
+    info!("Requeued {} dead letters, skipped {}, at external request", requeued, skipped);
+
+    Json(RequeuedResponseModel { requeued, skipped }).into_response()
+}
+
+async fn clear_dead_letters(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let removed = state.tg_state.lock().await.clear_dead_letters();
+    info!("Cleared {} dead letters, at external request", removed);
+
+    Json(RemovedResponseModel { removed }).into_response()
+}
+
+async fn rule_stats(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    let mut stats: BTreeMap<String, RuleStatsModel> = METRICS.rules.counters().into_iter().map(|(rule, counter)| {
+        (rule, RuleStatsModel { evaluated: counter.evaluations(), matched: counter.matches(), ..Default::default() })
+    }).collect();
+
+    for (rule, rule_stats) in state.tg_state.lock().await.rule_stats() {
+        stats.entry(rule.clone()).or_default().stats = rule_stats.clone();
+    }
+
+    Json(stats).into_response()
+}
+
+async fn template_stats(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    Json(state.tg_state.lock().await.template_stats().clone()).into_response()
+}
+
+async fn render_metrics(State(state): State<ServerState>) -> impl IntoResponse {
+    let queue_depths = state.tg_state.lock().await.queue_depths();
+    let wa_nations = state.cache.wa_nations.read().await.len();
+
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&queue_depths, wa_nations),
+    )
+}
+
+async fn reload(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::FORBIDDEN, "Invalid or missing key").into_response();
+    }
+
+    match reload_config(&state.config_path, &state.config, &state.tg_state).await {
+        Ok(()) => (StatusCode::OK, "Success").into_response(),
+        Err(err) => {
+            warn!("Failed to reload config at external request, keeping the old one: {}", err);
+            (StatusCode::UNPROCESSABLE_ENTITY, err).into_response()
+        }
+    }
+}
+
 pub async fn start_api_server(
     state: Arc<Mutex<TelegramState>>,
+    cache: Arc<Cache>,
+    config: SharedConfig,
+    config_path: String,
     key: String,
 ) -> Result<(), Box<dyn Error>> {
     let app = Router::new()
         .route("/queue", post(add_telegram))
-        .with_state(ServerState { tg_state: state, auth_key: key });
+        .route("/queues", get(list_queues))
+        .route("/queues/{name}", get(get_queue))
+        .route("/queues/{name}/clear", post(clear_queue))
+        .route("/queues/{name}/pause", post(pause_queue))
+        .route("/queues/{name}/resume", post(resume_queue))
+        .route("/queues/{name}/nations/{nation}", delete(remove_nation_from_queue))
+        .route("/queues/{name}/nations/{nation}/front", post(move_to_front))
+        .route("/nations/{nation}", delete(remove_nation_from_all_queues))
+        .route("/dead-letters", get(list_dead_letters).delete(clear_dead_letters))
+        .route("/dead-letters/requeue", post(requeue_dead_letters))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/reload", post(reload))
+        .route("/metrics", get(render_metrics))
+        .route("/stats/rules", get(rule_stats))
+        .route("/stats/templates", get(template_stats))
+        .with_state(ServerState { tg_state: state, cache, config, config_path, auth_key: key });
 
     tokio::spawn(async move {
         let listener = tokio::net::TcpListener::bind("0.0.0.0:6496").await.unwrap();
@@ -1,136 +1,1143 @@
 use caramel::ns::api::Client;
 use log::{info, warn};
-use std::{collections::VecDeque, sync::Arc, time::{Duration, Instant}};
-use tokio::sync::{Mutex, mpsc};
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::{Mutex, mpsc, watch};
 
-use crate::api::send_telegram;
+use crate::api::{SendResult, send_telegram};
+use crate::cache::Cache;
+use crate::config::{Eviction, QueueConfig, QueueOrder, TemplateConfig};
+use crate::metrics::METRICS;
+use crate::revalidate::{Revalidation, revalidate};
+use crate::schedule::Schedule;
+use crate::tracking::{RuleStats, TelegramOrigin, TemplateStats, Tracker};
+use crate::persist::{ClientSnapshot, QueueSnapshotRef, SentRecordRef, Snapshot, SnapshotRef, instant_to_unix, unix_now, unix_to_instant};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Telegram {
     pub nation: String,
     pub tgid: String,
     pub tg_key: String,
     pub client_key: String,
+    /// Unix timestamp (seconds) of when this telegram was added to its queue.
+    #[serde(default)]
+    pub queued_at: u64,
+    /// How many times sending this telegram has failed.
+    #[serde(default)]
+    pub attempts: u32,
+    /// Unix timestamp (seconds) before which this telegram won't be retried.
+    #[serde(default)]
+    pub retry_at: u64,
+    /// The rule that queued this telegram, if any.
+    #[serde(default)]
+    pub origin: Option<TelegramOrigin>,
+    /// Conditions to check again right before sending, from the rule that queued this telegram.
+    #[serde(default)]
+    pub revalidate: Vec<Revalidation>,
 }
 
 impl Telegram {
     pub fn new(nation: String, tgid: String, tg_key: String, client_key: String) -> Self {
-        Self { nation, tgid, tg_key, client_key }
+        Self { nation, tgid, tg_key, client_key, queued_at: unix_now(), attempts: 0, retry_at: 0, origin: None, revalidate: Vec::new() }
     }
 }
 
+/// A telegram that failed too many times, or can never be sent, kept along with why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub telegram: Telegram,
+    pub queue: String,
+    pub reason: String,
+    pub failed_at: u64,
+}
+
+/// A dead letter as shown by the API. Leaves out the telegram's secret key.
+#[derive(Debug, Serialize)]
+pub struct DeadLetterInfo {
+    pub nation: String,
+    pub tgid: String,
+    pub queue: String,
+    pub attempts: u32,
+    pub reason: String,
+    pub failed_at: u64,
+}
+
+const MAX_DEAD_LETTERS: usize = 1000;
+
+/// How often to drop expired telegrams from queues and prune pending conversions, in seconds.
+/// Expired telegrams are also dropped whenever a telegram is sent.
+const EXPIRY_SWEEP_INTERVAL: u64 = 60;
+
+/// How often to check whether queues held back by their schedule can send again, in seconds.
+const SCHEDULE_CHECK_INTERVAL: u64 = 60;
+
+/// A queued telegram as shown by the queue inspection API. Leaves out the telegram's secret key.
+#[derive(Debug, Serialize)]
+pub struct TelegramInfo {
+    pub nation: String,
+    pub tgid: String,
+    pub client_key: String,
+    pub queued_at: u64,
+    pub attempts: u32,
+}
+
+/// A snapshot of a queue as shown by the queue inspection API.
+#[derive(Debug, Serialize)]
+pub struct QueueInfo {
+    pub name: String,
+    pub length: usize,
+    pub ephemeral: bool,
+    pub recruitment: bool,
+    pub paused: bool,
+    /// Whether the queue is outside of its schedule, and holding back its telegrams until the schedule is active again.
+    pub outside_schedule: bool,
+    pub order: QueueOrder,
+    /// Unix timestamp (seconds) of when the next telegram in this queue is expected to be sent,
+    /// going by client rate limits alone. None if the queue is empty.
+    pub next_send_at: Option<u64>,
+    /// The next telegrams to be sent, in order.
+    pub telegrams: Vec<TelegramInfo>,
+}
+
 pub struct TelegramQueue {
     queue: VecDeque<Telegram>,
     identifier: String,
     ephemeral: bool,
     recruitment: bool,
+    max_length: Option<usize>,
+    eviction: Eviction,
+    max_age: Option<u64>,
+    order: QueueOrder,
+    clients: Vec<String>,
+    paused: bool,
+    schedule: Option<Schedule>,
+    revalidate: Vec<Revalidation>,
 }
 
 impl TelegramQueue {
-    pub fn new(identifier: String, ephemeral: bool, recruitment: bool) -> Self {
-        Self { queue: VecDeque::new(), identifier, ephemeral, recruitment }
+    pub fn new(identifier: String, config: &QueueConfig) -> Self {
+        Self { 
+            queue: VecDeque::new(), identifier, 
+            ephemeral: config.ephemeral, recruitment: config.recruitment,
+            max_length: config.max_length, eviction: config.eviction,
+            max_age: config.max_age, order: config.order,
+            clients: config.clients.clone(),
+            paused: config.paused,
+            schedule: config.schedule.clone(),
+            revalidate: config.revalidate.clone(),
+        }
+    }
+
+    /// Applies a new config to this queue, keeping the telegrams already in it if they still fit.
+    /// Returns any telegrams that were dropped.
+    pub fn reconfigure(&mut self, config: &QueueConfig) -> Vec<Telegram> {
+        self.ephemeral = config.ephemeral;
+        self.recruitment = config.recruitment;
+        self.max_length = config.max_length;
+        self.eviction = config.eviction;
+        self.max_age = config.max_age;
+        self.order = config.order;
+        self.clients = config.clients.clone();
+        self.schedule = config.schedule.clone();
+        self.revalidate = config.revalidate.clone();
+
+        let mut dropped = Vec::new();
+
+        if self.ephemeral && self.queue.len() > 1 {
+            // Ephemeral queues only ever hold the newest telegram
+            dropped.extend(self.queue.drain(..self.queue.len() - 1));
+        }
+
+        dropped.append(&mut self.truncate());
+        dropped
     }
 
     pub fn is_recruitment(&self) -> bool {
         self.recruitment
     }
 
-    pub fn enqueue_tg(&mut self, telegram: Telegram) {
+    /// Whether the queue's schedule allows sending right now.
+    pub fn is_active(&self, now: u64) -> bool {
+        self.schedule.as_ref().is_none_or(|schedule| schedule.is_active(now))
+    }
+
+    /// Drops telegrams until the queue fits in its maximum length, returning the dropped telegrams.
+    fn truncate(&mut self) -> Vec<Telegram> {
+        let mut dropped = Vec::new();
+
+        if let Some(max_length) = self.max_length {
+            while self.queue.len() > max_length {
+                // Requeued and reordered telegrams can be anywhere, so go by when they were queued.
+                // Ties go to the telegram queued first for drop_oldest, and last for drop_newest.
+                let index = match self.eviction {
+                    Eviction::DropOldest => self.queue.iter().enumerate()
+                        .min_by_key(|(_, telegram)| telegram.queued_at).map(|(index, _)| index),
+                    Eviction::DropNewest => self.queue.iter().enumerate()
+                        .max_by_key(|(_, telegram)| telegram.queued_at).map(|(index, _)| index),
+                };
+
+                if let Some(telegram) = index.and_then(|index| self.queue.remove(index)) {
+                    info!("Queue '{}' is full, dropping telegram to nation {}", self.identifier, telegram.nation);
+                    dropped.push(telegram);
+                }
+            }
+        }
+
+        dropped
+    }
+
+    /// How many more telegrams fit before new ones are turned away, or None if they never are.
+    /// Only full `drop_newest` queues turn new telegrams away, everything else drops queued ones to make room.
+    fn room(&self) -> Option<usize> {
+        match self.max_length {
+            Some(max_length) if self.eviction == Eviction::DropNewest && !self.ephemeral => {
+                Some(max_length.saturating_sub(self.queue.len()))
+            },
+            _ => None,
+        }
+    }
+
+    /// Drops telegrams that have been queued longer than the queue's maximum age, returning the dropped telegrams.
+    pub fn expire(&mut self, now: u64) -> Vec<Telegram> {
+        let Some(max_age) = self.max_age else { return Vec::new(); };
+
+        // Telegrams saved before enqueue times were recorded have a zero timestamp, and never expire
+        let is_expired = |telegram: &Telegram| telegram.queued_at > 0 && telegram.queued_at.saturating_add(max_age) <= now;
+
+        // This runs before every send, so only rebuild the queue if something actually expired
+        if !self.queue.iter().any(is_expired) {
+            return Vec::new();
+        }
+
+        let (expired, kept): (Vec<_>, Vec<_>) = self.queue.drain(..).partition(is_expired);
+        self.queue = kept.into();
+
+        for telegram in &expired {
+            info!(
+                "Dropping telegram {} to nation {} from queue '{}', it was queued {}s ago",
+                telegram.tgid, telegram.nation, self.identifier, now - telegram.queued_at
+            );
+        }
+
+        expired
+    }
+
+    /// Adds a telegram to the queue, returning any telegrams that were dropped to make room for it.
+    pub fn enqueue_tg(&mut self, telegram: Telegram) -> Vec<Telegram> {
+        let mut dropped = Vec::new();
+
         if self.ephemeral {
-            self.queue.clear();
+            dropped.extend(self.queue.drain(..));
         }
 
         self.queue.push_back(telegram);
+        dropped.append(&mut self.truncate());
+        dropped
     }
 
-    pub fn enqueue_tgs(&mut self, telegrams: Vec<Telegram>) {
+    /// Adds a batch of telegrams to the queue, returning any telegrams that were dropped to make room for them.
+    /// Batches are queued as if each telegram was added one by one, so LIFO queues send them last to first.
+    /// With `preserve_order`, they're sent in the order given instead, whatever the queue's order.
+    pub fn enqueue_tgs(&mut self, mut telegrams: Vec<Telegram>, preserve_order: bool) -> Vec<Telegram> {
+        let mut dropped = Vec::new();
+
+        if preserve_order && self.order == QueueOrder::Lifo && !self.ephemeral {
+            telegrams.reverse();
+        }
+
         if self.ephemeral {
-            if let Some(last) = telegrams.into_iter().last() {
-                self.queue.clear();
+            if let Some(last) = telegrams.pop() {
+                dropped.extend(self.queue.drain(..));
+                dropped.append(&mut telegrams);
                 self.queue.push_back(last);
             }
         } else {
             self.queue.append(&mut telegrams.into());
+            dropped.append(&mut self.truncate());
+        }
+
+        dropped
+    }
+
+    /// Iterates over queued telegrams in the order they will be sent, along with their index.
+    pub fn iter_pending(&self) -> impl Iterator<Item = (usize, &Telegram)> {
+        let len = self.queue.len();
+        (0..len).map(move |i| match self.order {
+            QueueOrder::Lifo => len - 1 - i,
+            QueueOrder::Fifo => i,
+        }).map(|i| (i, &self.queue[i]))
+    }
+
+    /// Client keys that may be used to send a telegram from this queue.
+    fn client_candidates<'a>(&'a self, telegram: &'a Telegram) -> &'a [String] {
+        if self.clients.is_empty() {
+            std::slice::from_ref(&telegram.client_key)
+        } else {
+            self.clients.as_slice()
+        }
+    }
+
+    pub fn take_tg(&mut self, index: usize) -> Option<Telegram> {
+        self.queue.remove(index)
+    }
+
+    /// Puts a telegram that couldn't be sent back, so it's the next one sent from this queue.
+    /// Returns the telegram if it was dropped instead.
+    pub fn requeue_tg(&mut self, telegram: Telegram) -> Option<Telegram> {
+        if self.ephemeral && !self.queue.is_empty() {
+            info!("Not retrying telegram to nation {}, a newer one replaced it in queue '{}'", telegram.nation, self.identifier);
+            return Some(telegram);
+        }
+
+        match self.order {
+            QueueOrder::Lifo => self.queue.push_back(telegram),
+            QueueOrder::Fifo => self.queue.push_front(telegram),
+        }
+
+        None
+    }
+
+    /// Removes every telegram to `nation`, returning the removed telegrams.
+    pub fn remove_nation(&mut self, nation: &str) -> Vec<Telegram> {
+        // Most events don't involve queued nations, so only rebuild the queue if there's something to remove
+        if !self.queue.iter().any(|telegram| telegram.nation == nation) {
+            return Vec::new();
         }
+
+        let (removed, kept): (Vec<_>, Vec<_>) = self.queue.drain(..).partition(|telegram| telegram.nation == nation);
+        self.queue = kept.into();
+        removed
     }
 
-    pub fn dequeue_tg(&mut self) -> Option<Telegram> {
-        self.queue.pop_back()
+    /// Removes every telegram, returning the removed telegrams.
+    pub fn clear(&mut self) -> Vec<Telegram> {
+        self.queue.drain(..).collect()
+    }
+
+    /// Moves the next telegram to `nation` so it's the next one sent from this queue.
+    pub fn move_to_front(&mut self, nation: &str) -> bool {
+        let Some((index, _)) = self.iter_pending().find(|(_, telegram)| telegram.nation == nation) else {
+            return false;
+        };
+
+        if let Some(telegram) = self.queue.remove(index) {
+            match self.order {
+                QueueOrder::Lifo => self.queue.push_back(telegram),
+                QueueOrder::Fifo => self.queue.push_front(telegram),
+            }
+        }
+
+        true
     }
 }
 
+/// Rate limiting state for a single API client key.
+/// NationStates rate limits each client separately, so every key gets its own schedule.
+#[derive(Debug, Clone)]
+pub struct ClientSchedule {
+    last_recruitment_time: Option<Instant>,
+    last_telegram_time: Option<Instant>,
+    /// Whether a telegram is currently being sent with this key.
+    busy: bool,
+    /// Set after a rate limit or server error, nothing is sent with this key until then.
+    blocked_until: Option<Instant>,
+}
+
+impl ClientSchedule {
+    fn new(started: Instant) -> Self {
+        Self { last_recruitment_time: Some(started), last_telegram_time: None, busy: false, blocked_until: None }
+    }
+
+    /// How long until this client can send a telegram from a (recruitment) queue, or None if it can send now.
+    pub fn delay(&self, recruitment: bool) -> Option<Duration> {
+        let blocked = self.blocked_until.map(
+            |until| until.saturating_duration_since(Instant::now())
+        ).filter(|delay| !delay.is_zero());
+        let delay = calculate_delay(&self.last_telegram_time, NORMAL_TELEGRAM_INTERVAL).max(blocked);
+
+        if recruitment {
+            delay.max(calculate_recruit_delay(&self.last_recruitment_time))
+        } else {
+            delay
+        }
+    }
+
+    fn mark_sent(&mut self) {
+        let now = Instant::now();
+        self.last_recruitment_time = Some(now);
+        self.last_telegram_time = Some(now);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnqueueError {
+    NoSuchQueue,
+    /// The same template is already queued for this nation, or being sent to it.
+    AlreadyQueued,
+    /// The same template was sent to this nation within the send history window.
+    RecentlySent,
+    /// The queue is full and drops new telegrams rather than queued ones.
+    QueueFull,
+}
+
+impl std::fmt::Display for EnqueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchQueue => write!(f, "no such queue"),
+            Self::AlreadyQueued => write!(f, "already queued"),
+            Self::RecentlySent => write!(f, "recently sent"),
+            Self::QueueFull => write!(f, "queue is full"),
+        }
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 pub struct TelegramState {
     queues: Vec<TelegramQueue>,
+    /// When each (nation, tgid) pair was last sent, used to block re-sends within `history_days`.
+    history: HashMap<(String, String), u64>,
+    history_days: u64,
+    /// (nation, tgid) pairs taken out of their queue to be sent, until sending finishes and they're in `history`.
+    in_flight: HashSet<(String, String)>,
+    /// While paused, telegrams are still queued but nothing is sent.
+    paused: bool,
+    clients: HashMap<String, ClientSchedule>,
+    /// Client keys that NationStates rejected. Their telegrams stay queued until the config is reloaded (see `update_templates`).
+    disabled_clients: HashSet<String>,
+    /// Telegram IDs that NationStates rejected. Their telegrams stay queued until the config is reloaded (see `update_templates`).
+    disabled_templates: HashSet<String>,
+    dead_letters: Vec<DeadLetter>,
+    tracker: Tracker,
+    max_retries: u32,
+    retry_backoff: u64,
+    started: Instant,
     signal: Option<mpsc::Sender<()>>,
+    writer: Option<watch::Sender<String>>,
 }
 
 impl TelegramState {
-    pub fn new() -> Self {
-        let mut state = Self { queues: Vec::new(), signal: None };
-        state.queues.push(TelegramQueue::new("recruit-permanent".into(), false, true));
-        state.queues.push(TelegramQueue::new("recruit-ephemeral".into(), true, true));
-        state.queues.push(TelegramQueue::new("regional".into(), false, false));
-        state
+    /// Creates one queue per entry in `queues`, which must already be sorted by priority.
+    pub fn new(queues: &[(String, QueueConfig)], paused: bool) -> Self {
+        Self { 
+            queues: queues.iter().map(
+                |(name, config)| TelegramQueue::new(name.clone(), config)
+            ).collect(), 
+            paused,
+            history: HashMap::new(),
+            history_days: 0,
+            in_flight: HashSet::new(),
+            clients: HashMap::new(),
+            disabled_clients: HashSet::new(),
+            disabled_templates: HashSet::new(),
+            dead_letters: Vec::new(),
+            tracker: Tracker::default(),
+            max_retries: 0,
+            retry_backoff: 0,
+            started: Instant::now(),
+            signal: None, writer: None, 
+        }
     }
 
-    pub async fn add_telegram_to_queue(&mut self, queue_name: &str, telegram: Telegram) -> bool {
-        for queue in &mut self.queues {
-            if queue.identifier == queue_name {
-                queue.enqueue_tg(telegram);
+    /// Reloads queued telegrams and per-client send times from a saved snapshot.
+    /// Telegrams for queues that no longer exist are dropped.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.tracker.restore(snapshot.tracker);
+
+        for saved in snapshot.queues {
+            if let Some(queue) = self.queues.iter_mut().find(|q| q.identifier == saved.identifier) {
+                info!("Restored {} telegrams to queue '{}'", saved.telegrams.len(), saved.identifier);
+                // Saved telegrams are already in queue order
+                let dropped = queue.enqueue_tgs(saved.telegrams, false);
+                queue.paused |= saved.paused;
+                self.record_dropped(dropped);
+            } else {
+                warn!("Dropping {} saved telegrams for unknown queue '{}'", saved.telegrams.len(), saved.identifier);
+            }
+        }
+
+        self.paused |= snapshot.paused;
+        self.dead_letters = snapshot.dead_letters;
+
+        for record in snapshot.history {
+            self.history.insert((record.nation, record.tgid), record.sent_at);
+        }
+        self.prune_history();
 
-                if let Some(signal) = &mut self.signal {
-                    signal.send(()).await.unwrap_or_else(|err| {
-                        warn!("Error notifying telegram loop of new nation: {err:?}");
-                    });
+        for (key, saved) in snapshot.clients {
+            self.clients.insert(key, ClientSchedule {
+                last_recruitment_time: saved.last_recruitment_time.and_then(unix_to_instant),
+                last_telegram_time: saved.last_telegram_time.and_then(unix_to_instant),
+                busy: false,
+                blocked_until: None,
+            });
+        }
+    }
+
+    /// Replaces the queue definitions after a config reload.
+    /// Existing queues keep their telegrams, queues that are no longer defined are dropped.
+    pub fn update_queues(&mut self, queues: &[(String, QueueConfig)]) {
+        let mut old_queues = std::mem::take(&mut self.queues);
+
+        for (name, config) in queues {
+            if let Some(index) = old_queues.iter().position(|q| &q.identifier == name) {
+                let mut queue = old_queues.swap_remove(index);
+                let dropped = queue.reconfigure(config);
+                self.queues.push(queue);
+                self.record_dropped(dropped);
+            } else {
+                info!("Adding new queue '{}'", name);
+                self.queues.push(TelegramQueue::new(name.clone(), config));
+            }
+        }
+
+        for queue in old_queues {
+            warn!("Queue '{}' was removed from config, dropping {} telegrams", queue.identifier, queue.queue.len());
+        }
+
+        self.save();
+        self.notify();
+    }
+
+    /// Gives queued telegrams and dead letters the credentials of their template in the new config,
+    /// found by the template they were queued from or by their TGID. Telegrams parked on rejected
+    /// credentials that no template matches anymore are dropped, since they could never be sent.
+    pub fn update_templates(&mut self, templates: &HashMap<String, TemplateConfig>) {
+        let disabled_clients = std::mem::take(&mut self.disabled_clients);
+        let disabled_templates = std::mem::take(&mut self.disabled_templates);
+        let is_parked = |telegram: &Telegram| {
+            disabled_clients.contains(&telegram.client_key) || disabled_templates.contains(&telegram.tgid)
+        };
+
+        let mut dropped = Vec::new();
+        for queue in &mut self.queues {
+            let count = dropped.len();
+            for mut telegram in std::mem::take(&mut queue.queue) {
+                if refresh_credentials(&mut telegram, templates) || !is_parked(&telegram) {
+                    queue.queue.push_back(telegram);
+                } else {
+                    dropped.push(telegram);
                 }
+            }
 
-                return true;
+            if dropped.len() > count {
+                warn!(
+                    "Dropping {} telegrams from queue '{}': their credentials were rejected and no template in the config matches them",
+                    dropped.len() - count, queue.identifier
+                );
             }
         }
+        self.record_dropped(dropped);
+
+        let count = self.dead_letters.len();
+        self.dead_letters.retain_mut(|letter| refresh_credentials(&mut letter.telegram, templates) || !is_parked(&letter.telegram));
+        if self.dead_letters.len() < count {
+            warn!(
+                "Dropping {} dead letters: their credentials were rejected and no template in the config matches them",
+                count - self.dead_letters.len()
+            );
+        }
 
-        return false;
+        self.save();
+        self.notify();
+    }
+
+    /// Saves the current state through `writer` every time it changes.
+    pub fn persist_to(&mut self, writer: watch::Sender<String>) {
+        self.writer = Some(writer);
+        self.save();
+    }
+
+    fn snapshot(&self) -> SnapshotRef<'_> {
+        SnapshotRef {
+            queues: self.queues.iter().map(|queue| QueueSnapshotRef {
+                identifier: &queue.identifier,
+                telegrams: &queue.queue,
+                paused: queue.paused,
+            }).collect(),
+            clients: self.clients.iter().map(|(key, schedule)| (key.as_str(), ClientSnapshot {
+                last_recruitment_time: schedule.last_recruitment_time.map(instant_to_unix),
+                last_telegram_time: schedule.last_telegram_time.map(instant_to_unix),
+            })).collect(),
+            paused: self.paused,
+            dead_letters: &self.dead_letters,
+            tracker: &self.tracker,
+            history: self.history.iter().map(|((nation, tgid), sent_at)| SentRecordRef {
+                nation, tgid, sent_at: *sent_at,
+            }).collect(),
+        }
+    }
+
+    /// Hands the current state to the state writer. Telegrams being sent aren't in any queue, so they aren't saved:
+    /// if the process stops mid-send they're lost, rather than risk sending them twice after a restart.
+    fn save(&self) {
+        if let Some(writer) = &self.writer {
+            match serde_json::to_string(&self.snapshot()) {
+                Ok(contents) => { writer.send_replace(contents); },
+                Err(err) => warn!("Failed to serialize state: {err}"),
+            }
+        }
     }
 
-    pub async fn add_telegrams_to_queue(&mut self, queue_name: &str, telegrams: Vec<Telegram>) -> bool {
+    /// Wakes up the telegram loop so it can pick up new work.
+    fn notify(&self) {
+        if let Some(signal) = &self.signal {
+            // A full channel means the loop already has a wakeup pending
+            if let Err(mpsc::error::TrySendError::Closed(_)) = signal.try_send(()) {
+                warn!("Error notifying telegram loop: channel closed");
+            }
+        }
+    }
+
+    pub fn client_schedule(&self, client_key: &str) -> ClientSchedule {
+        self.clients.get(client_key).cloned().unwrap_or_else(|| ClientSchedule::new(self.started))
+    }
+
+    fn client_schedule_mut(&mut self, client_key: &str) -> &mut ClientSchedule {
+        let started = self.started;
+        self.clients.entry(client_key.to_string()).or_insert_with(|| ClientSchedule::new(started))
+    }
+
+    /// Sets how many times a failed telegram is retried, and the base delay in seconds between retries.
+    /// The delay doubles with every failed attempt.
+    pub fn set_retry_policy(&mut self, max_retries: u32, retry_backoff: u64) {
+        self.max_retries = max_retries;
+        self.retry_backoff = retry_backoff;
+    }
+
+    fn retry_delay(&self, attempts: u32) -> u64 {
+        self.retry_backoff.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY)
+    }
+
+    fn add_dead_letter(&mut self, queue_name: &str, telegram: Telegram, reason: String) {
+        warn!("Giving up on telegram {} to nation {} ({}): {}", telegram.tgid, telegram.nation, queue_name, reason);
+
+        self.dead_letters.push(DeadLetter { telegram, queue: queue_name.to_string(), reason, failed_at: unix_now() });
+        if self.dead_letters.len() > MAX_DEAD_LETTERS {
+            self.dead_letters.remove(0);
+        }
+    }
+
+    pub fn describe_dead_letters(&self) -> Vec<DeadLetterInfo> {
+        self.dead_letters.iter().map(|letter| DeadLetterInfo {
+            nation: letter.telegram.nation.clone(),
+            tgid: letter.telegram.tgid.clone(),
+            queue: letter.queue.clone(),
+            attempts: letter.telegram.attempts,
+            reason: letter.reason.clone(),
+            failed_at: letter.failed_at,
+        }).collect()
+    }
+
+    /// Puts dead letters back into their queues with a fresh retry count and enqueue time.
+    /// Only requeues letters to the given nations, or all of them if `nations` is None.
+    /// Letters whose telegram is already queued or was recently sent, or whose queue is full, stay in the list.
+    /// Returns how many were requeued and how many were skipped.
+    pub fn requeue_dead_letters(&mut self, nations: Option<&[String]>) -> (usize, usize) {
+        let (selected, kept): (Vec<DeadLetter>, Vec<DeadLetter>) = std::mem::take(&mut self.dead_letters).into_iter().partition(
+            |letter| nations.is_none_or(|nations| nations.contains(&letter.telegram.nation))
+        );
+        self.dead_letters = kept;
+
+        let (mut requeued, mut skipped) = (0, 0);
+        let now = unix_now();
+        for mut letter in selected {
+            if self.check_duplicate(&letter.telegram).is_err() {
+                self.dead_letters.push(letter);
+                skipped += 1;
+                continue;
+            }
+
+            letter.telegram.attempts = 0;
+            letter.telegram.retry_at = 0;
+            // Count the queue's max age from now, or letters older than it would expire straight away
+            letter.telegram.queued_at = now;
+
+            let Some(index) = self.queues.iter().position(|queue| queue.identifier == letter.queue) else {
+                warn!("Can't requeue telegram to nation {}, queue '{}' no longer exists", letter.telegram.nation, letter.queue);
+                continue;
+            };
+
+            if self.queues[index].room() == Some(0) {
+                self.dead_letters.push(letter);
+                skipped += 1;
+                continue;
+            }
+
+            self.tracker.record_enqueued(&letter.telegram);
+            let dropped = self.queues[index].enqueue_tg(letter.telegram);
+            self.record_dropped(dropped);
+            requeued += 1;
+        }
+
+        self.save();
+        self.notify();
+        (requeued, skipped)
+    }
+
+    /// Removes every dead letter, returning how many were removed.
+    pub fn clear_dead_letters(&mut self) -> usize {
+        let count = self.dead_letters.len();
+        self.dead_letters.clear();
+        self.save();
+        count
+    }
+
+    /// Sets how long a nation is blocked from receiving the same template again after being sent it.
+    /// Zero disables the send history.
+    pub fn set_history_days(&mut self, days: u64) {
+        self.history_days = days;
+        self.prune_history();
+    }
+
+    fn prune_history(&mut self) {
+        let cutoff = unix_now().saturating_sub(self.history_days.saturating_mul(SECONDS_PER_DAY));
+        self.history.retain(|_, sent_at| *sent_at > cutoff);
+    }
+
+    fn record_history(&mut self, telegram: &Telegram) {
+        if self.history_days == 0 { return; }
+
+        self.history.insert((telegram.nation.clone(), telegram.tgid.clone()), unix_now());
+        self.prune_history();
+    }
+
+    /// Whether a telegram with this template is queued for the nation, or being sent to it.
+    fn is_pending(&self, nation: &str, tgid: &str) -> bool {
+        self.in_flight.contains(&(nation.to_string(), tgid.to_string())) || self.queues.iter().any(
+            |queue| queue.queue.iter().any(|telegram| telegram.nation == nation && telegram.tgid == tgid)
+        )
+    }
+
+    fn check_duplicate(&self, telegram: &Telegram) -> Result<(), EnqueueError> {
+        if self.is_pending(&telegram.nation, &telegram.tgid) {
+            return Err(EnqueueError::AlreadyQueued);
+        }
+
+        let cutoff = unix_now().saturating_sub(self.history_days.saturating_mul(SECONDS_PER_DAY));
+        match self.history.get(&(telegram.nation.clone(), telegram.tgid.clone())) {
+            Some(sent_at) if *sent_at > cutoff => Err(EnqueueError::RecentlySent),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn add_telegram_to_queue(&mut self, queue_name: &str, telegram: Telegram) -> Result<(), EnqueueError> {
+        let Some(room) = self.queues.iter().find(|queue| queue.identifier == queue_name).map(TelegramQueue::room) else {
+            return Err(EnqueueError::NoSuchQueue);
+        };
+
+        self.check_duplicate(&telegram)?;
+
+        // Turned away before counting it, since it would be dropped as soon as it was queued
+        if room == Some(0) {
+            return Err(EnqueueError::QueueFull);
+        }
+
+        self.tracker.record_enqueued(&telegram);
+
+        if let Some(queue) = self.queue_mut(queue_name) {
+            let dropped = queue.enqueue_tg(telegram);
+            self.record_dropped(dropped);
+        }
+
+        METRICS.enqueued.inc(queue_name);
+
+        self.save();
+        self.notify();
+        Ok(())
+    }
+
+    /// Adds a batch of telegrams to a queue, skipping any that would be duplicates or don't fit in a full queue.
+    /// Returns how many telegrams were added.
+    pub async fn add_telegrams_to_queue(
+        &mut self, queue_name: &str, telegrams: Vec<Telegram>, preserve_order: bool
+    ) -> Result<usize, EnqueueError> {
+        let Some(room) = self.queues.iter().find(|queue| queue.identifier == queue_name).map(TelegramQueue::room) else {
+            return Err(EnqueueError::NoSuchQueue);
+        };
+
+        let mut accepted: Vec<Telegram> = Vec::new();
+        for telegram in telegrams {
+            let in_batch = accepted.iter().any(|t| t.nation == telegram.nation && t.tgid == telegram.tgid);
+
+            if in_batch || self.check_duplicate(&telegram).is_err() {
+                info!("Skipping duplicate telegram {} to nation {}", telegram.tgid, telegram.nation);
+            } else {
+                accepted.push(telegram);
+            }
+        }
+
+        // Turned away before counting them, since they would be dropped as soon as they were queued
+        if let Some(room) = room && accepted.len() > room {
+            for telegram in accepted.split_off(room) {
+                info!("Queue '{}' is full, turning away telegram to nation {}", queue_name, telegram.nation);
+            }
+        }
+
+        let count = accepted.len();
+        for telegram in &accepted {
+            self.tracker.record_enqueued(telegram);
+        }
+
+        if let Some(queue) = self.queue_mut(queue_name) {
+            let dropped = queue.enqueue_tgs(accepted, preserve_order);
+            self.record_dropped(dropped);
+        }
+
+        METRICS.enqueued.inc_by(queue_name, count as u64);
+
+        self.save();
+        self.notify();
+        Ok(count)
+    }
+
+    /// Estimates how long until the next telegram in `queue` can be sent.
+    fn next_send_delay(&self, queue: &TelegramQueue) -> Option<Duration> {
+        let (_, telegram) = queue.iter_pending().next()?;
+
+        queue.client_candidates(telegram).iter().map(|client_key| {
+            let schedule = self.client_schedule(client_key);
+
+            if schedule.busy {
+                // Currently sending, so it'll have to wait out a full interval afterwards
+                Duration::from_secs(if queue.is_recruitment() { RECRUITMENT_TELEGRAM_INTERVAL } else { NORMAL_TELEGRAM_INTERVAL })
+            } else {
+                schedule.delay(queue.is_recruitment()).unwrap_or_default()
+            }
+        }).min()
+    }
+
+    fn queue_info(&self, queue: &TelegramQueue, limit: usize) -> QueueInfo {
+        QueueInfo {
+            name: queue.identifier.clone(),
+            length: queue.queue.len(),
+            ephemeral: queue.ephemeral,
+            recruitment: queue.recruitment,
+            paused: queue.paused,
+            outside_schedule: !queue.is_active(unix_now()),
+            order: queue.order,
+            next_send_at: self.next_send_delay(queue).map(|delay| unix_now() + delay.as_secs()),
+            telegrams: queue.iter_pending().take(limit).map(|(_, telegram)| TelegramInfo {
+                nation: telegram.nation.clone(),
+                tgid: telegram.tgid.clone(),
+                client_key: telegram.client_key.clone(),
+                queued_at: telegram.queued_at,
+                attempts: telegram.attempts,
+            }).collect(),
+        }
+    }
+
+    /// Current length of every queue, in priority order.
+    pub fn queue_depths(&self) -> Vec<(String, usize)> {
+        self.queues.iter().map(|queue| (queue.identifier.clone(), queue.queue.len())).collect()
+    }
+
+    /// Describes every queue in priority order, listing up to `limit` upcoming telegrams for each.
+    pub fn describe_queues(&self, limit: usize) -> Vec<QueueInfo> {
+        self.queues.iter().map(|queue| self.queue_info(queue, limit)).collect()
+    }
+
+    /// Describes a single queue, listing up to `limit` upcoming telegrams.
+    pub fn describe_queue(&self, queue_name: &str, limit: usize) -> Option<QueueInfo> {
+        self.queues.iter().find(|queue| queue.identifier == queue_name).map(|queue| self.queue_info(queue, limit))
+    }
+
+    fn queue_mut(&mut self, queue_name: &str) -> Option<&mut TelegramQueue> {
+        self.queues.iter_mut().find(|queue| queue.identifier == queue_name)
+    }
+
+    /// Removes every telegram to `nation` from one queue, or from all queues if `queue_name` is None.
+    /// Returns how many were removed, or None if the queue doesn't exist.
+    pub fn remove_nation(&mut self, queue_name: Option<&str>, nation: &str) -> Option<usize> {
+        let removed = match queue_name {
+            Some(queue_name) => self.queue_mut(queue_name)?.remove_nation(nation),
+            None => self.queues.iter_mut().flat_map(|queue| queue.remove_nation(nation)).collect(),
+        };
+
+        let count = removed.len();
+        if count > 0 {
+            self.record_dropped(removed);
+            self.save();
+        }
+
+        Some(count)
+    }
+
+    /// Removes every telegram from a queue. Returns how many were removed, or None if the queue doesn't exist.
+    pub fn clear_queue(&mut self, queue_name: &str) -> Option<usize> {
+        let removed = self.queue_mut(queue_name)?.clear();
+
+        let count = removed.len();
+        self.record_dropped(removed);
+        self.save();
+
+        Some(count)
+    }
+
+    /// Makes the telegram to `nation` the next one sent from its queue.
+    /// Returns whether it was found, or None if the queue doesn't exist.
+    pub fn move_to_front(&mut self, queue_name: &str, nation: &str) -> Option<bool> {
+        let moved = self.queue_mut(queue_name)?.move_to_front(nation);
+
+        if moved {
+            self.save();
+            self.notify();
+        }
+
+        Some(moved)
+    }
+
+    /// Pauses or resumes sending from every queue.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.save();
+        self.notify();
+    }
+
+    /// Pauses or resumes sending from a single queue. Returns None if the queue doesn't exist.
+    pub fn set_queue_paused(&mut self, queue_name: &str, paused: bool) -> Option<()> {
+        self.queue_mut(queue_name)?.paused = paused;
+        self.save();
+        self.notify();
+        Some(())
+    }
+
+    /// Drops telegrams older than their queue's maximum age from every queue.
+    fn expire_telegrams(&mut self, now: u64) {
+        let mut expired = Vec::new();
+
         for queue in &mut self.queues {
-            if queue.identifier == queue_name {
-                queue.enqueue_tgs(telegrams);
+            let mut dropped = queue.expire(now);
+            if !dropped.is_empty() {
+                METRICS.expired.inc_by(&queue.identifier, dropped.len() as u64);
+                expired.append(&mut dropped);
+            }
+        }
 
-                if let Some(signal) = &mut self.signal {
-                    signal.send(()).await.unwrap_or_else(|err| {
-                        warn!("Error notifying telegram loop of new nation: {err:?}");
-                    });
+        if !expired.is_empty() {
+            self.record_dropped(expired);
+            self.save();
+        }
+    }
+
+    /// Finds the highest priority telegram that can be sent right now, removes it from its queue
+    /// and reserves the client key it will be sent with. Returns it along with the checks to run on it before sending.
+    /// If nothing can be sent, returns how long to wait until something might be.
+    fn next_telegram(&mut self) -> Result<(String, Telegram, Vec<Revalidation>), Option<Duration>> {
+        if self.paused { return Err(None); }
+
+        let mut wait: Option<Duration> = None;
+        let mut found = None;
+        let now = unix_now();
+
+        self.expire_telegrams(now);
+
+        'queues: for (queue_index, queue) in self.queues.iter().enumerate() {
+            if queue.paused { continue; }
+
+            if !queue.is_active(now) {
+                // Schedules can change at any minute, so check again then
+                if !queue.queue.is_empty() {
+                    let delay = Duration::from_secs(SCHEDULE_CHECK_INTERVAL);
+                    wait = Some(wait.map_or(delay, |wait| wait.min(delay)));
+                }
+                continue;
+            }
+
+            for (index, telegram) in queue.iter_pending() {
+                if self.disabled_templates.contains(&telegram.tgid) { continue; }
+
+                if telegram.retry_at > now {
+                    let delay = Duration::from_secs(telegram.retry_at - now);
+                    wait = Some(wait.map_or(delay, |wait| wait.min(delay)));
+                    continue;
                 }
 
-                return true;
+                for client_key in queue.client_candidates(telegram) {
+                    if self.disabled_clients.contains(client_key) { continue; }
+
+                    let schedule = self.client_schedule(client_key);
+                    if schedule.busy { continue; }
+
+                    match schedule.delay(queue.is_recruitment()) {
+                        None => {
+                            found = Some((queue_index, index, client_key.clone()));
+                            break 'queues;
+                        },
+                        Some(delay) => {
+                            wait = Some(wait.map_or(delay, |wait| wait.min(delay)));
+                        }
+                    }
+                }
             }
         }
 
-        return false;
+        let Some((queue_index, index, client_key)) = found else { return Err(wait); };
+        let queue = &mut self.queues[queue_index];
+        let Some(mut telegram) = queue.take_tg(index) else { return Err(wait); };
+        let queue_name = queue.identifier.clone();
+
+        let mut checks = queue.revalidate.clone();
+        for check in &telegram.revalidate {
+            if !checks.contains(check) { checks.push(check.clone()); }
+        }
+        
+        info!("Sending telegram {} to nation {} ({}, client {})", telegram.tgid, telegram.nation, queue_name, client_key);
+
+        telegram.client_key = client_key.clone();
+        self.client_schedule_mut(&client_key).busy = true;
+        self.in_flight.insert((telegram.nation.clone(), telegram.tgid.clone()));
+        self.save();
+
+        Ok((queue_name, telegram, checks))
+    }
+
+    fn requeue_tg(&mut self, queue_name: &str, telegram: Telegram) {
+        let dropped = match self.queue_mut(queue_name) {
+            Some(queue) => queue.requeue_tg(telegram),
+            None => {
+                warn!("Not retrying telegram to nation {}, queue '{}' no longer exists", telegram.nation, queue_name);
+                Some(telegram)
+            },
+        };
+
+        self.record_dropped(dropped.into_iter().collect());
+    }
+
+    fn record_dropped(&mut self, dropped: Vec<Telegram>) {
+        for telegram in &dropped {
+            self.tracker.record_dropped(telegram);
+        }
+    }
+
+    /// Checks whether a nation moving to `region` converts any telegram sent to it.
+    pub fn record_move(&mut self, nation: &str, region: &str) {
+        if self.tracker.record_move(nation, region, unix_now()) {
+            self.save();
+        }
+    }
+
+    /// Forgets telegrams that can no longer convert because they were sent too long ago.
+    pub fn prune_conversions(&mut self, now: u64) {
+        if self.tracker.prune(now) {
+            self.save();
+        }
+    }
+
+    /// Sets how long after a telegram is sent a move to its target region counts as a conversion, in seconds.
+    pub fn set_conversion_window(&mut self, window: u64) {
+        self.tracker.set_window(window);
+    }
+
+    pub fn rule_stats(&self) -> &BTreeMap<String, RuleStats> {
+        self.tracker.rule_stats()
+    }
+
+    pub fn template_stats(&self) -> &BTreeMap<String, TemplateStats> {
+        self.tracker.template_stats()
+    }
+
+    /// Updates the client's schedule and the telegram's queue based on how sending it went.
+    fn finish_telegram(&mut self, queue_name: &str, telegram: Telegram, result: SendResult) {
+        let result_reason = result.to_string();
+        self.in_flight.remove(&(telegram.nation.clone(), telegram.tgid.clone()));
+
+        let schedule = self.client_schedule_mut(&telegram.client_key);
+        schedule.busy = false;
+
+        match result {
+            SendResult::Queued => {
+                schedule.mark_sent();
+                self.record_history(&telegram);
+                self.tracker.record_sent(&telegram, unix_now());
+            },
+            SendResult::InvalidClientKey => {
+                warn!("Client key {} is not registered for the API, parking its telegrams until the config is reloaded", telegram.client_key);
+                self.disabled_clients.insert(telegram.client_key.clone());
+                self.requeue_tg(queue_name, telegram);
+            },
+            SendResult::InvalidTemplate => {
+                warn!("Telegram {} was rejected (wrong TGID or secret key), parking its telegrams until the config is reloaded", telegram.tgid);
+                self.disabled_templates.insert(telegram.tgid.clone());
+                self.requeue_tg(queue_name, telegram);
+            },
+            SendResult::NoSuchNation => {
+                self.add_dead_letter(queue_name, telegram, result_reason);
+            },
+            SendResult::Skipped(reason) => {
+                info!("Not sending telegram {} to nation {} ({}): {}", telegram.tgid, telegram.nation, queue_name, reason);
+                self.record_dropped(vec![telegram]);
+            },
+            SendResult::RateLimited(retry_after) => {
+                let retry_after = retry_after.unwrap_or(Duration::from_secs(RECRUITMENT_TELEGRAM_INTERVAL));
+                warn!("Client key {} was rate limited, retrying in {}s", telegram.client_key, retry_after.as_secs());
+                schedule.blocked_until = Some(Instant::now() + retry_after);
+                self.requeue_tg(queue_name, telegram);
+            },
+            SendResult::ServerError(_) => {
+                schedule.blocked_until = Some(Instant::now() + Duration::from_secs(NORMAL_TELEGRAM_INTERVAL));
+
+                let mut telegram = telegram;
+                telegram.attempts += 1;
+
+                if telegram.attempts > self.max_retries {
+                    self.add_dead_letter(queue_name, telegram, result_reason);
+                } else {
+                    let delay = self.retry_delay(telegram.attempts);
+                    warn!(
+                        "Error sending telegram {} to nation {}, retrying in {}s (attempt {}/{}): {}", 
+                        telegram.tgid, telegram.nation, delay, telegram.attempts, self.max_retries, result_reason
+                    );
+
+                    telegram.retry_at = unix_now() + delay;
+                    self.requeue_tg(queue_name, telegram);
+                }
+            },
+        }
+
+        self.save();
+        self.notify();
     }
 }
 
+/// Copies the credentials of the telegram's template onto it, returning whether one was found.
+/// Telegrams queued by a rule look up the template they came from, others the template with the same TGID.
+fn refresh_credentials(telegram: &mut Telegram, templates: &HashMap<String, TemplateConfig>) -> bool {
+    let template = telegram.origin.as_ref()
+        .and_then(|origin| templates.get(&origin.template))
+        .or_else(|| templates.values().find(|template| template.tgid == telegram.tgid));
+
+    let Some(template) = template else { return false; };
+    telegram.tgid = template.tgid.clone();
+    telegram.tg_key = template.tg_key.clone();
+    telegram.client_key = template.client_key.clone();
+    true
+}
+
+/// Upper bound for the delay between retries of a failed telegram, in seconds.
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+
 // Add one second of buffer time just in case
 const RECRUITMENT_TELEGRAM_INTERVAL: u64 = 181;
 const NORMAL_TELEGRAM_INTERVAL: u64 = 31;
 
-fn can_recruit(last_recruitment_time: &Instant) -> bool {
-    calculate_recruit_delay(last_recruitment_time).is_none()
-}
+fn calculate_delay(last_time: &Option<Instant>, interval: u64) -> Option<Duration> {
+    let Some(last_time) = last_time else { return None; };
+    let time_since_last = Instant::now().duration_since(*last_time).as_secs();
 
-fn calculate_recruit_delay(last_recruitment_time: &Instant) -> Option<Duration> {
-    let time_since_last_recruit = Instant::now().duration_since(*last_recruitment_time).as_secs();
-
-    if time_since_last_recruit >= RECRUITMENT_TELEGRAM_INTERVAL {
+    if time_since_last >= interval {
         return None;
     }
 
-    Some(Duration::from_secs(RECRUITMENT_TELEGRAM_INTERVAL - time_since_last_recruit))
+    Some(Duration::from_secs(interval - time_since_last))
+}
+
+fn calculate_recruit_delay(last_recruitment_time: &Option<Instant>) -> Option<Duration> {
+    calculate_delay(last_recruitment_time, RECRUITMENT_TELEGRAM_INTERVAL)
 }
 
-async fn telegram_loop(client: Arc<Client>, state: Arc<Mutex<TelegramState>>) {
-    let mut last_recruitment_time = Instant::now();
 
+async fn telegram_loop(client: Arc<Client>, state: Arc<Mutex<TelegramState>>, cache: Arc<Cache>) {
     let (tx, mut rx) = mpsc::channel(100);
 
     {
@@ -139,52 +1146,371 @@ async fn telegram_loop(client: Arc<Client>, state: Arc<Mutex<TelegramState>>) {
     }
 
     loop {
-        let mut state = state.lock().await;
+        let mut guard = state.lock().await;
 
         // Clear the signal queue
-        while let Ok(_) = rx.try_recv() {}
-
-        let mut sent = false;
-        let mut recruit_delay: Option<Duration> = None;
+        while rx.try_recv().is_ok() {}
 
-        for queue in &mut state.queues {
-            if queue.is_recruitment() && !can_recruit(&last_recruitment_time) { 
-                recruit_delay = calculate_recruit_delay(&last_recruitment_time);
-                continue;
-            }
+        let next = guard.next_telegram();
+        drop(guard); // Unlock mutex before blocking
 
-            if let Some(telegram) = queue.dequeue_tg() {
-                info!("Sending telegram {} to nation {} ({})", telegram.tgid, telegram.nation, &queue.identifier);
+        match next {
+            Ok((queue_name, telegram, checks)) => {
+                // Send in the background, so other client keys don't have to wait for this one
+                let client = client.clone();
+                let state = state.clone();
+                let cache = cache.clone();
 
-                send_telegram(&client, telegram).await.unwrap_or_else(|err| {
-                    warn!("Error sending telegram: {err:?}");
+                tokio::spawn(async move {
+                    // Check the nation still qualifies, now that it's reached the front of its queue
+                    let result = match revalidate(&cache, &telegram.nation, &checks).await {
+                        Ok(()) => send_telegram(&client, &telegram).await,
+                        Err(reason) => SendResult::Skipped(reason),
+                    };
+                    state.lock().await.finish_telegram(&queue_name, telegram, result);
                 });
-
-                last_recruitment_time = Instant::now();
-
-                sent = true;
-                break;
-            }
-        }
-
-        drop(state); // Unlock mutex before blocking
-
-        if !sent {
-            // Wait for a nation to be added to queue, or for the recruitment timeout to expire, whichever happens first
-            if let Some(delay) = recruit_delay {
+            },
+            Err(Some(delay)) => {
+                // Wait for a nation to be added to queue, or for the earliest timeout to expire, whichever happens first
                 tokio::select! {
                     _ = rx.recv() => {},
                     _ = tokio::time::sleep(delay) => {},
                 }
-            } else {
+            },
+            Err(None) => {
                 rx.recv().await;
             }
-        } else {
-            tokio::time::sleep(Duration::from_secs(NORMAL_TELEGRAM_INTERVAL)).await;
         }
     }
 }
 
-pub fn start_telegram_loop(client: Arc<Client>, state: Arc<Mutex<TelegramState>>) {
-    tokio::spawn(async { telegram_loop(client, state).await; });
-}
\ No newline at end of file
+/// Periodically drops expired telegrams, so they don't linger in queues that aren't sending, e.g. while paused,
+/// and forgets pending conversions past the conversion window.
+async fn expiry_sweep(state: Arc<Mutex<TelegramState>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(EXPIRY_SWEEP_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let now = unix_now();
+        let mut state = state.lock().await;
+        state.expire_telegrams(now);
+        state.prune_conversions(now);
+    }
+}
+
+pub fn start_telegram_loop(client: Arc<Client>, state: Arc<Mutex<TelegramState>>, cache: Arc<Cache>) {
+    tokio::spawn(expiry_sweep(state.clone()));
+    tokio::spawn(async { telegram_loop(client, state, cache).await; });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_config(order: QueueOrder, ephemeral: bool, max_length: Option<usize>, eviction: Eviction) -> QueueConfig {
+        QueueConfig {
+            ephemeral, recruitment: true, priority: 0, max_length, eviction, max_age: None, order,
+            clients: Vec::new(), paused: false, schedule: None, revalidate: Vec::new(),
+        }
+    }
+
+    fn queue(order: QueueOrder, ephemeral: bool, max_length: Option<usize>, eviction: Eviction) -> TelegramQueue {
+        TelegramQueue::new("test".into(), &queue_config(order, ephemeral, max_length, eviction))
+    }
+
+    fn telegrams(nations: &[&str], queued_at: u64) -> Vec<Telegram> {
+        nations.iter().map(|nation| Telegram {
+            queued_at, ..Telegram::new(nation.to_string(), "1".into(), "key".into(), "client".into())
+        }).collect()
+    }
+
+    fn nations(telegrams: &[Telegram]) -> Vec<&str> {
+        telegrams.iter().map(|telegram| telegram.nation.as_str()).collect()
+    }
+
+    /// Nations in the order they'll be sent.
+    fn pending(queue: &TelegramQueue) -> Vec<&str> {
+        queue.iter_pending().map(|(_, telegram)| telegram.nation.as_str()).collect()
+    }
+
+    #[test]
+    fn lifo_sends_batches_last_to_first() {
+        let mut queue = queue(QueueOrder::Lifo, false, None, Eviction::DropOldest);
+        queue.enqueue_tgs(telegrams(&["a", "b", "c"], 100), false);
+        queue.enqueue_tgs(telegrams(&["d", "e"], 200), false);
+
+        assert_eq!(pending(&queue), ["e", "d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn fifo_sends_batches_in_order() {
+        let mut queue = queue(QueueOrder::Fifo, false, None, Eviction::DropOldest);
+        queue.enqueue_tgs(telegrams(&["a", "b", "c"], 100), false);
+        queue.enqueue_tgs(telegrams(&["d", "e"], 200), false);
+
+        assert_eq!(pending(&queue), ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn preserve_order_keeps_batches_in_order() {
+        let mut lifo = queue(QueueOrder::Lifo, false, None, Eviction::DropOldest);
+        lifo.enqueue_tgs(telegrams(&["a", "b", "c"], 100), true);
+        lifo.enqueue_tgs(telegrams(&["d", "e"], 200), true);
+
+        // Newer batches still go first in a LIFO queue, but each one is sent in the order given
+        assert_eq!(pending(&lifo), ["d", "e", "a", "b", "c"]);
+
+        let mut fifo = queue(QueueOrder::Fifo, false, None, Eviction::DropOldest);
+        fifo.enqueue_tgs(telegrams(&["a", "b", "c"], 100), true);
+        fifo.enqueue_tgs(telegrams(&["d", "e"], 200), true);
+
+        assert_eq!(pending(&fifo), ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn ephemeral_queues_keep_the_last_of_a_batch() {
+        let mut queue = queue(QueueOrder::Lifo, true, None, Eviction::DropOldest);
+        assert!(queue.enqueue_tgs(telegrams(&["a"], 100), false).is_empty());
+
+        let dropped = queue.enqueue_tgs(telegrams(&["b", "c", "d"], 200), true);
+        assert_eq!(nations(&dropped), ["a", "b", "c"]);
+        assert_eq!(pending(&queue), ["d"]);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_older_telegrams_first() {
+        let mut queue = queue(QueueOrder::Fifo, false, Some(3), Eviction::DropOldest);
+        queue.enqueue_tgs(telegrams(&["a", "b"], 100), false);
+
+        let dropped = queue.enqueue_tgs(telegrams(&["c", "d"], 200), false);
+        assert_eq!(nations(&dropped), ["a"]);
+        assert_eq!(pending(&queue), ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_start_of_a_batch_with_one_queued_at() {
+        let mut fifo = queue(QueueOrder::Fifo, false, Some(2), Eviction::DropOldest);
+        let dropped = fifo.enqueue_tgs(telegrams(&["a", "b", "c", "d"], 100), false);
+
+        assert_eq!(nations(&dropped), ["a", "b"]);
+        assert_eq!(pending(&fifo), ["c", "d"]);
+
+        // Batches with a preserved order are queued last to first in LIFO queues, so the end of the batch goes
+        let mut lifo = queue(QueueOrder::Lifo, false, Some(2), Eviction::DropOldest);
+        let dropped = lifo.enqueue_tgs(telegrams(&["a", "b", "c", "d"], 100), true);
+
+        assert_eq!(nations(&dropped), ["d", "c"]);
+        assert_eq!(pending(&lifo), ["a", "b"]);
+    }
+
+    #[test]
+    fn drop_newest_evicts_the_end_of_a_batch_with_one_queued_at() {
+        let mut queue = queue(QueueOrder::Fifo, false, Some(3), Eviction::DropNewest);
+        queue.enqueue_tgs(telegrams(&["a"], 100), false);
+
+        let dropped = queue.enqueue_tgs(telegrams(&["b", "c", "d", "e"], 200), false);
+        assert_eq!(nations(&dropped), ["e", "d"]);
+        assert_eq!(pending(&queue), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn only_drop_newest_queues_turn_telegrams_away() {
+        let mut drop_newest = queue(QueueOrder::Fifo, false, Some(3), Eviction::DropNewest);
+        drop_newest.enqueue_tgs(telegrams(&["a"], 100), false);
+        assert_eq!(drop_newest.room(), Some(2));
+
+        drop_newest.enqueue_tgs(telegrams(&["b", "c"], 100), false);
+        assert_eq!(drop_newest.room(), Some(0));
+
+        let drop_oldest = queue(QueueOrder::Fifo, false, Some(3), Eviction::DropOldest);
+        assert_eq!(drop_oldest.room(), None);
+
+        let ephemeral = queue(QueueOrder::Fifo, true, Some(3), Eviction::DropNewest);
+        assert_eq!(ephemeral.room(), None);
+    }
+
+    #[test]
+    fn snapshots_restore_the_saved_state() {
+        let queues = [("test".to_string(), queue_config(QueueOrder::Fifo, false, None, Eviction::DropOldest))];
+        let mut state = TelegramState::new(&queues, false);
+        state.set_history_days(30);
+        state.queues[0].enqueue_tgs(telegrams(&["a", "b"], 100), false);
+        state.history.insert(("c".into(), "1".into()), unix_now());
+        state.add_dead_letter("test", telegrams(&["d"], 100).remove(0), "server error".into());
+
+        let contents = serde_json::to_string(&state.snapshot()).unwrap();
+        let mut restored = TelegramState::new(&queues, false);
+        restored.set_history_days(30);
+        restored.restore(serde_json::from_str(&contents).unwrap());
+
+        assert_eq!(pending(&restored.queues[0]), ["a", "b"]);
+        assert_eq!(restored.history.len(), 1);
+        assert_eq!(restored.history, state.history);
+        assert_eq!(nations(&restored.dead_letters.iter().map(|letter| letter.telegram.clone()).collect::<Vec<_>>()), ["d"]);
+    }
+
+    fn telegram_with_client(nation: &str, client_key: &str) -> Telegram {
+        Telegram::new(nation.into(), "1".into(), "key".into(), client_key.into())
+    }
+
+    /// A client that has never sent anything, so it can send right away.
+    fn idle_client() -> ClientSchedule {
+        ClientSchedule { last_recruitment_time: None, last_telegram_time: None, busy: false, blocked_until: None }
+    }
+
+    fn two_queue_state() -> TelegramState {
+        let mut normal = queue_config(QueueOrder::Fifo, false, None, Eviction::DropOldest);
+        normal.recruitment = false;
+
+        let queues = [
+            ("recruitment".to_string(), queue_config(QueueOrder::Fifo, false, None, Eviction::DropOldest)),
+            ("normal".to_string(), normal),
+        ];
+
+        TelegramState::new(&queues, false)
+    }
+
+    #[test]
+    fn clients_waiting_on_recruitment_delay_dont_hold_up_other_clients() {
+        let mut state = two_queue_state();
+        state.queues[0].enqueue_tg(telegram_with_client("a", "recruiter"));
+        state.queues[1].enqueue_tg(telegram_with_client("b", "other"));
+
+        // Just sent a recruitment telegram, so it has to wait out the recruitment interval
+        state.clients.insert("recruiter".into(), ClientSchedule { last_recruitment_time: Some(Instant::now()), ..idle_client() });
+        state.clients.insert("other".into(), idle_client());
+
+        let (queue_name, telegram, _) = state.next_telegram().unwrap();
+        assert_eq!((queue_name.as_str(), telegram.nation.as_str(), telegram.client_key.as_str()), ("normal", "b", "other"));
+        assert!(state.client_schedule("other").busy);
+
+        // The other client is busy sending, and the recruiter still has most of its interval to go
+        let wait = state.next_telegram().unwrap_err().unwrap();
+        assert!(wait > Duration::from_secs(RECRUITMENT_TELEGRAM_INTERVAL - 5));
+        assert_eq!(pending(&state.queues[0]), ["a"]);
+    }
+
+    #[test]
+    fn skipped_telegrams_dont_use_up_the_slot() {
+        let mut state = two_queue_state();
+        state.queues[0].enqueue_tgs(vec![telegram_with_client("a", "recruiter"), telegram_with_client("b", "recruiter")], false);
+        state.clients.insert("recruiter".into(), idle_client());
+
+        let (queue_name, telegram, _) = state.next_telegram().unwrap();
+        assert_eq!(telegram.nation, "a");
+        assert!(state.next_telegram().is_err(), "the client is busy while sending");
+
+        state.finish_telegram(&queue_name, telegram, SendResult::Skipped("nation no longer exists".into()));
+
+        let schedule = state.client_schedule("recruiter");
+        assert!(!schedule.busy);
+        assert_eq!(schedule.last_recruitment_time, None);
+
+        let (_, telegram, _) = state.next_telegram().unwrap();
+        assert_eq!(telegram.nation, "b");
+    }
+
+    #[test]
+    fn sent_telegrams_use_up_the_slot() {
+        let mut state = two_queue_state();
+        state.queues[0].enqueue_tgs(vec![telegram_with_client("a", "recruiter"), telegram_with_client("b", "recruiter")], false);
+        state.clients.insert("recruiter".into(), idle_client());
+
+        let (queue_name, telegram, _) = state.next_telegram().unwrap();
+        state.finish_telegram(&queue_name, telegram, SendResult::Queued);
+
+        assert!(state.next_telegram().unwrap_err().is_some_and(|wait| wait > Duration::from_secs(RECRUITMENT_TELEGRAM_INTERVAL - 5)));
+        assert_eq!(pending(&state.queues[0]), ["b"]);
+    }
+
+    #[test]
+    fn rate_limits_only_block_that_client() {
+        let mut state = two_queue_state();
+        state.queues[0].enqueue_tgs(vec![telegram_with_client("a", "limited"), telegram_with_client("b", "other")], false);
+        state.clients.insert("limited".into(), idle_client());
+        state.clients.insert("other".into(), idle_client());
+
+        let (queue_name, telegram, _) = state.next_telegram().unwrap();
+        assert_eq!(telegram.client_key, "limited");
+        state.finish_telegram(&queue_name, telegram, SendResult::RateLimited(Some(Duration::from_secs(600))));
+
+        // The rate limited telegram goes back to the front, but only the other client can send
+        let (_, telegram, _) = state.next_telegram().unwrap();
+        assert_eq!((telegram.nation.as_str(), telegram.client_key.as_str()), ("b", "other"));
+
+        let wait = state.next_telegram().unwrap_err().unwrap();
+        assert!(wait > Duration::from_secs(590));
+        assert_eq!(pending(&state.queues[0]), ["a"]);
+    }
+
+    #[test]
+    fn telegrams_being_sent_count_as_duplicates() {
+        let mut state = two_queue_state();
+        state.set_history_days(30);
+        state.queues[0].enqueue_tg(telegram_with_client("a", "recruiter"));
+        state.clients.insert("recruiter".into(), idle_client());
+
+        let (queue_name, telegram, _) = state.next_telegram().unwrap();
+        assert_eq!(state.check_duplicate(&telegram_with_client("a", "other")), Err(EnqueueError::AlreadyQueued));
+
+        state.finish_telegram(&queue_name, telegram, SendResult::Queued);
+        assert_eq!(state.check_duplicate(&telegram_with_client("a", "other")), Err(EnqueueError::RecentlySent));
+
+        state.history.clear();
+        assert_eq!(state.check_duplicate(&telegram_with_client("a", "other")), Ok(()));
+    }
+
+    #[test]
+    fn reloading_templates_refreshes_or_drops_parked_telegrams() {
+        let mut state = two_queue_state();
+        let from_rule = Telegram {
+            origin: Some(TelegramOrigin { rule: "welcome".into(), template: "welcome".into(), target_region: None }),
+            ..telegram_with_client("a", "old")
+        };
+        let orphaned = Telegram::new("b".into(), "2".into(), "key".into(), "old".into());
+        let from_api = Telegram::new("c".into(), "3".into(), "key".into(), "api".into());
+        state.queues[0].enqueue_tgs(vec![from_rule, orphaned, from_api], false);
+        state.disabled_clients.insert("old".into());
+
+        let templates = HashMap::from([("welcome".to_string(), TemplateConfig {
+            tgid: "1".into(), tg_key: "new key".into(), client_key: "new".into(),
+        })]);
+        state.update_templates(&templates);
+
+        assert_eq!(pending(&state.queues[0]), ["a", "c"]);
+        let refreshed = &state.queues[0].queue[0];
+        assert_eq!((refreshed.tg_key.as_str(), refreshed.client_key.as_str()), ("new key", "new"));
+        assert_eq!(state.queues[0].queue[1].client_key, "api");
+        assert!(state.disabled_clients.is_empty());
+    }
+
+    #[test]
+    fn requeueing_skips_letters_that_are_already_queued() {
+        let mut state = two_queue_state();
+        let from_rule = Telegram {
+            origin: Some(TelegramOrigin { rule: "welcome".into(), template: "welcome".into(), target_region: None }),
+            ..telegram_with_client("a", "recruiter")
+        };
+        state.add_dead_letter("recruitment", from_rule, "nation not found".into());
+        state.add_dead_letter("recruitment", telegram_with_client("b", "recruiter"), "nation not found".into());
+        state.queues[0].enqueue_tg(telegram_with_client("b", "recruiter"));
+
+        assert_eq!(state.requeue_dead_letters(None), (1, 1));
+        assert_eq!(pending(&state.queues[0]), ["b", "a"]);
+        assert_eq!(state.describe_dead_letters().iter().map(|letter| letter.nation.as_str()).collect::<Vec<_>>(), ["b"]);
+        assert_eq!(state.rule_stats()["welcome"].enqueued, 1);
+    }
+
+    #[test]
+    fn requeueing_leaves_letters_for_full_queues() {
+        let queues = [("bounded".to_string(), queue_config(QueueOrder::Fifo, false, Some(1), Eviction::DropNewest))];
+        let mut state = TelegramState::new(&queues, false);
+        state.add_dead_letter("bounded", telegram_with_client("a", "recruiter"), "nation not found".into());
+        state.add_dead_letter("bounded", telegram_with_client("b", "recruiter"), "nation not found".into());
+
+        assert_eq!(state.requeue_dead_letters(None), (1, 1));
+        assert_eq!(pending(&state.queues[0]), ["a"]);
+        assert_eq!(state.describe_dead_letters().iter().map(|letter| letter.nation.as_str()).collect::<Vec<_>>(), ["b"]);
+    }
+}
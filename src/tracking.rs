@@ -0,0 +1,189 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::tgloop::Telegram;
+
+/// Where a telegram queued by a rule came from, used to attribute sends and conversions to the rule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelegramOrigin {
+    pub rule: String,
+    pub template: String,
+    /// Region the rule is recruiting for. A nation moving there after being telegrammed counts as a conversion.
+    pub target_region: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuleStats {
+    /// Telegrams this rule added to a queue.
+    pub enqueued: u64,
+    /// Telegrams from this rule that were sent.
+    pub sent: u64,
+    /// Telegrams from this rule that were dropped before being sent, e.g. replaced in an ephemeral queue.
+    pub dropped: u64,
+    /// Nations that moved to the target region within the conversion window after being sent a telegram.
+    pub conversions: u64,
+}
+
+/// Outcomes of telegrams using a template, to compare templates against each other.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TemplateStats {
+    /// Telegrams using this template added to a queue.
+    pub enqueued: u64,
+    /// Telegrams using this template that were sent.
+    pub sent: u64,
+    /// Nations that moved to the target region within the conversion window after being sent this template.
+    pub conversions: u64,
+    /// Sent telegrams from rules with a target region, which are the only ones that can convert.
+    pub tracked_sent: u64,
+    /// Conversions per tracked sent telegram.
+    pub conversion_rate: f64,
+}
+
+impl TemplateStats {
+    fn update_rate(&mut self) {
+        self.conversion_rate = match self.tracked_sent {
+            0 => 0.0,
+            sends => self.conversions as f64 / sends as f64,
+        };
+    }
+}
+
+/// A sent telegram that hasn't converted yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingConversion {
+    pub rule: String,
+    pub template: String,
+    pub nation: String,
+    pub region: String,
+    pub sent_at: u64,
+}
+
+/// Per-rule statistics and conversion tracking for telegrams queued by rules.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tracker {
+    rules: BTreeMap<String, RuleStats>,
+    #[serde(default)]
+    templates: BTreeMap<String, TemplateStats>,
+    /// Pending conversions, by nation.
+    pending: HashMap<String, Vec<PendingConversion>>,
+    /// How long after sending a move to the target region still counts as a conversion, in seconds.
+    #[serde(skip)]
+    window: u64,
+}
+
+impl Tracker {
+    pub fn set_window(&mut self, window: u64) {
+        self.window = window;
+    }
+
+    /// Replaces the recorded statistics with saved ones, keeping the current conversion window.
+    pub fn restore(&mut self, saved: Tracker) {
+        self.rules = saved.rules;
+        self.templates = saved.templates;
+        self.pending = saved.pending;
+    }
+
+    fn rule(&mut self, rule: &str) -> &mut RuleStats {
+        if !self.rules.contains_key(rule) {
+            self.rules.insert(rule.to_string(), RuleStats::default());
+        }
+
+        self.rules.get_mut(rule).expect("rule stats were just inserted")
+    }
+
+    fn template(&mut self, template: &str) -> &mut TemplateStats {
+        if !self.templates.contains_key(template) {
+            self.templates.insert(template.to_string(), TemplateStats::default());
+        }
+
+        self.templates.get_mut(template).expect("template stats were just inserted")
+    }
+
+    pub fn record_enqueued(&mut self, telegram: &Telegram) {
+        if let Some(origin) = &telegram.origin {
+            self.rule(&origin.rule).enqueued += 1;
+            self.template(&origin.template).enqueued += 1;
+        }
+    }
+
+    pub fn record_dropped(&mut self, telegram: &Telegram) {
+        if let Some(origin) = &telegram.origin {
+            self.rule(&origin.rule).dropped += 1;
+        }
+    }
+
+    pub fn record_sent(&mut self, telegram: &Telegram, now: u64) {
+        let Some(origin) = &telegram.origin else { return; };
+        self.rule(&origin.rule).sent += 1;
+        self.template(&origin.template).sent += 1;
+
+        if let Some(region) = &origin.target_region && self.window > 0 {
+            let stats = self.template(&origin.template);
+            stats.tracked_sent += 1;
+            stats.update_rate();
+
+            self.pending.entry(telegram.nation.clone()).or_default().push(PendingConversion {
+                rule: origin.rule.clone(),
+                template: origin.template.clone(),
+                nation: telegram.nation.clone(),
+                region: region.clone(),
+                sent_at: now,
+            });
+        }
+    }
+
+    /// Counts a conversion for every pending telegram to `nation` that was recruiting for `region`.
+    /// Returns whether any conversion was counted.
+    pub fn record_move(&mut self, nation: &str, region: &str, now: u64) -> bool {
+        let Some(pending) = self.pending.get_mut(nation) else { return false; };
+        let (converted, remaining): (Vec<_>, Vec<_>) = pending.drain(..).partition(|p| p.region == region);
+        *pending = remaining;
+
+        if pending.is_empty() {
+            self.pending.remove(nation);
+        }
+
+        // Pending conversions are only pruned periodically, so some may already be past the window
+        let cutoff = now.saturating_sub(self.window);
+        let mut recorded = false;
+
+        for conversion in converted.into_iter().filter(|p| p.sent_at >= cutoff) {
+            info!(
+                "Nation '{}' moved to '{}' after being sent template '{}' (rule '{}')",
+                nation, region, conversion.template, conversion.rule
+            );
+            self.rule(&conversion.rule).conversions += 1;
+
+            let stats = self.template(&conversion.template);
+            stats.conversions += 1;
+            stats.update_rate();
+            recorded = true;
+        }
+
+        recorded
+    }
+
+    /// Forgets pending conversions older than the conversion window. Returns whether any were forgotten.
+    pub fn prune(&mut self, now: u64) -> bool {
+        let cutoff = now.saturating_sub(self.window);
+        let mut pruned = false;
+
+        self.pending.retain(|_, pending| {
+            let len = pending.len();
+            pending.retain(|p| p.sent_at >= cutoff);
+            pruned |= pending.len() != len;
+            !pending.is_empty()
+        });
+
+        pruned
+    }
+
+    pub fn rule_stats(&self) -> &BTreeMap<String, RuleStats> {
+        &self.rules
+    }
+
+    pub fn template_stats(&self) -> &BTreeMap<String, TemplateStats> {
+        &self.templates
+    }
+}